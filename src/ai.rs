@@ -7,25 +7,135 @@ use bevy::{
 use bevy_rapier2d::prelude::*;
 use nalgebra::{point, vector};
 use rand::Rng;
+use std::collections::HashMap;
 
 use crate::player;
 use crate::lighting;
-use crate::level;
+use crate::level_data;
+use crate::archetype::{Archetype, EnemyArchetypes};
+use crate::exposure::LightExposure;
 use crate::gamestate::GameState;
+use crate::guard_state::{sight_clarity, GuardBrain, GuardState};
+use crate::mirror::Mirrors;
+use crate::nav::{FlowField, NavGrid};
+use crate::search::SearchPlan;
+use crate::smoke::{smoke_blocks_line, SmokeCloud};
+use crate::steering::steer;
 
 pub struct AiPlugin;
 
 impl Plugin for AiPlugin {
     fn build(&self, app: &mut AppBuilder) {
-        app.add_system_set(SystemSet::on_update(GameState::Playing)
-            .with_system(ai_perception_system.system())
-            .with_system(ai_movement_system.system())
-            .with_system(ai_chase_behavior_system.system())
-            .with_system(ai_perception_debug_system.system())
-        );
+        app
+            .add_asset::<EnemyArchetypes>()
+            .init_asset_loader::<EnemyArchetypes>()
+            .add_startup_system(load_archetypes.system())
+            .add_event::<NoiseEvent>()
+            .add_event::<GuardAlertEvent>()
+            .insert_resource(GuardDebug{show_state: false})
+            .insert_resource(SearchClaims::default())
+            .insert_resource(NavGrid::default())
+            .insert_resource(ChaseField{field: None})
+            .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(ai_perception_system.system())
+                .with_system(ai_hearing_system.system())
+                .with_system(ai_radio_system.system())
+                .with_system(ai_chase_field_system.system())
+                .with_system(ai_movement_system.system())
+                .with_system(ai_guard_behavior_system.system())
+                .with_system(ai_perception_debug_system.system())
+                .with_system(ai_state_text_system.system())
+                .with_system(ai_detection_meter_system.system())
+            );
     }
 }
 
+// Kinds of enemy that levels can place, kept loaded for the level builder
+pub struct ArchetypeLibrary {
+    pub handle: Handle<EnemyArchetypes>,
+}
+
+fn load_archetypes(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(ArchetypeLibrary { handle: asset_server.load("enemies.archetypes") });
+}
+
+// Something guards can hear, any guard within the radius that can walk to it close enough goes to investigate
+pub struct NoiseEvent {
+    pub position: Vec2,
+    pub radius: f32,
+}
+
+// Sound travels around walls a bit further than in a straight line
+const NOISE_PATH_FACTOR: f32 = 1.5;
+
+// A guard with the player in sight calling out where they are, guards within the caller's radio range
+// or that can see the caller come to search there
+pub struct GuardAlertEvent {
+    pub position: Vec2,
+    // Which way the player was going
+    pub heading: Vec2,
+    pub from: Vec2,
+    pub caller: Entity,
+}
+
+// Seconds between call-outs while a guard keeps the player in sight
+const RADIO_INTERVAL: f32 = 1.0;
+
+pub struct AiRadio {
+    pub range: f32,
+    // Seconds until this guard calls out again
+    cooldown: f32,
+}
+
+impl AiRadio {
+    pub fn new(range: f32) -> AiRadio {
+        AiRadio { range, cooldown: 0.0 }
+    }
+}
+
+// Shared route to the player for every guard chasing them
+pub struct ChaseField {
+    field: Option<FlowField>,
+}
+
+// The grid cell each searching guard is heading to, so the others can pick somewhere else to look
+#[derive(Default)]
+pub struct SearchClaims(HashMap<Entity, level_data::GridPos>);
+
+// Search spreads out from the last sighting, this many tiles at first and a tile further every few seconds
+const SEARCH_START_STEPS: u32 = 3;
+const SEARCH_SPREAD_RATE: f32 = 0.3;
+// Room a guard leaves between itself and walls when cutting across open space, a little under half its sprite
+const GUARD_RADIUS: f32 = 18.0;
+
+// Cells a guard checks before giving up the search
+const SEARCH_BUDGET: u32 = 8;
+
+// Where a searching guard has looked, started afresh whenever it has somewhere new to search
+pub struct AiSearch {
+    plan: Option<SearchPlan>,
+    // The sighting the plan was made for
+    target: Vec2,
+}
+
+// A player standing in lamp light at least this bright can be seen from further away
+const LIT_THRESHOLD: f32 = 0.1;
+const LIT_RANGE_MULTIPLIER: f32 = 1.5;
+
+const DETECTION_METER_WIDTH: f32 = 36.0;
+
+// Bar above each guard showing how close it is to spotting the player, the fill is the part that grows
+pub struct DetectionMeter {
+    fill: bool,
+}
+
+pub struct GuardDebug {
+    pub show_state: bool,
+}
+
+// Label above each guard showing its current state, toggled with F3
+pub struct GuardStateText;
+
 pub struct Facing {
     pub angle: f32,
     pub turn_rate: f32
@@ -60,7 +170,7 @@ impl Facing {
         self.turn_towards(target_forward.y.atan2(target_forward.x), turn_rate_mult);
     }
 
-    pub fn _turn(&mut self, direction: f32, turn_rate_mult: f32) {
+    pub fn turn(&mut self, direction: f32, turn_rate_mult: f32) {
         let change_amt = direction.signum() * self.turn_rate * turn_rate_mult;
         self.angle += change_amt;
 
@@ -77,9 +187,13 @@ pub struct AiPerception {
     pub visual_range: f32,
     pub vision_cone_angle: f32,
     can_see_target: bool,
+    // How well the target can be seen this frame, 0 when it can't be seen, see guard_state::sight_clarity
+    pub clarity: f32,
     target_position: Vec2,
+    // Direction the target was moving when last seen, zero if it isn't known
+    target_heading: Vec2,
     target_direction: f32,
-    last_seen_time: f64,
+    home_point: Vec2,
 }
 
 impl AiPerception {
@@ -88,16 +202,22 @@ impl AiPerception {
             visual_range,
             vision_cone_angle,
             can_see_target: false,
+            clarity: 0.0,
             target_position: home_point,
+            target_heading: Vec2::default(),
             target_direction: 0.0,
-            last_seen_time: 0.0,
+            home_point,
         }
     }
 }
 
 pub struct AiMovement {
     pub move_speed: f32,
+    // Speed the guard was spawned with, behaviours scale their speeds from this
+    pub base_speed: f32,
     move_to_target: bool,
+    // Heading for the player, so the shared chase flow field can be followed instead of a path
+    chasing: bool,
     target_position: Vec2,
     current_path: Vec<Vec2>,
     path_index: usize
@@ -107,7 +227,9 @@ impl AiMovement {
     pub fn new(move_speed: f32, start_dest: Vec2) -> AiMovement {
         AiMovement {
             move_speed,
+            base_speed: move_speed,
             move_to_target: true,
+            chasing: false,
             target_position: start_dest,
             current_path: vec![],
             path_index: 0,
@@ -118,6 +240,13 @@ impl AiMovement {
     pub fn move_to(&mut self, target: Vec2) {
         self.target_position = target;
         self.move_to_target = true;
+        self.chasing = false;
+    }
+
+    // Like move_to but for going after the player
+    pub fn chase(&mut self, target: Vec2) {
+        self.move_to(target);
+        self.chasing = true;
     }
     
     pub fn is_moving(&self) -> bool {
@@ -132,6 +261,56 @@ impl AiMovement {
 
 pub struct AiChaseBehavior;
 
+pub struct AiPatrol {
+    route: Vec<Vec2>,
+    // Seconds spent looking around at each waypoint
+    pause: f32,
+    next_waypoint: usize,
+    // Seconds spent at the current waypoint so far, None while walking between waypoints
+    waiting: Option<f32>,
+    look_angle: f32,
+}
+
+impl AiPatrol {
+    pub fn new(route: Vec<Vec2>, pause: f32) -> AiPatrol {
+        AiPatrol {
+            route,
+            pause,
+            next_waypoint: 0,
+            waiting: None,
+            look_angle: 0.0,
+        }
+    }
+
+    // Where to pick the route back up after being drawn away, the waypoint the guard was on its way to
+    fn resume(&mut self) -> Vec2 {
+        self.waiting = None;
+        self.route[self.next_waypoint]
+    }
+
+    // Walks the route in a loop, sweeping the view cone side to side at each waypoint
+    fn update(&mut self, mover: &mut AiMovement, facing: &mut Facing, delta: f32) {
+        match self.waiting {
+            None => {
+                if !mover.is_moving() {
+                    self.waiting = Some(0.0);
+                    self.look_angle = facing.angle;
+                }
+            }
+            Some(waited) if waited >= self.pause => {
+                self.waiting = None;
+                self.next_waypoint = (self.next_waypoint + 1) % self.route.len();
+                mover.move_to(self.route[self.next_waypoint]);
+            }
+            Some(waited) => {
+                let sweep = std::f32::consts::FRAC_PI_2 * f32::sin(waited * 2.0);
+                facing.turn_towards(self.look_angle + sweep, delta);
+                self.waiting = Some(waited + delta);
+            }
+        }
+    }
+}
+
 pub struct AiPerceptionDebugIndicator;
 
 pub fn spawn_enemy(commands: &mut Commands,
@@ -141,12 +320,20 @@ pub fn spawn_enemy(commands: &mut Commands,
     meshes: &mut ResMut<Assets<Mesh>>,
     render_data: & ResMut<lighting::LightRenderData>,
     pos: Vec2,
+    archetype: &Archetype,
+    patrol_route: Vec<Vec2>,
 ) {
+    let visual_range = archetype.visual_range;
+    let vision_cone_angle = f32::to_radians(archetype.vision_cone_angle);
+    let [light_r, light_g, light_b] = archetype.light_color;
+    let spotlight = lighting::SpotLight::new(vision_cone_angle, Color::rgb(light_r, light_g, light_b), visual_range)
+        .with_softness(archetype.light_softness);
+
     // Load sprite
     let circle_texture_handle: Handle<Texture> = asset_server.load("sprites/circle.png");
 
-    let sprite_size_x = 40.0;
-    let sprite_size_y = 40.0;
+    let sprite_size_x = archetype.size;
+    let sprite_size_y = archetype.size;
 
     let collider_size_x = sprite_size_x / rapier_config.scale;
     let collider_size_y = sprite_size_y / rapier_config.scale;
@@ -165,16 +352,22 @@ pub fn spawn_enemy(commands: &mut Commands,
     })
     .insert_bundle(ColliderBundle {
         position: [(pos.x / rapier_config.scale) + collider_size_x / 2.0, (pos.y / rapier_config.scale) + collider_size_y / 2.0].into(),
-        material: ColliderMaterial { friction: 0.0, restitution: 0.9, ..Default::default() },
+        // Guards steer clear of each other, so bumping should be rare and not send them flying
+        material: ColliderMaterial { friction: 0.0, restitution: 0.1, ..Default::default() },
         ..Default::default()
     })
     .insert(ColliderPositionSync::Discrete)
-    .insert(Facing::new(std::f32::consts::FRAC_PI_2))
-    .insert(AiPerception::new(500.0, f32::to_radians(25.0), pos))
-    .insert(AiMovement::new(150.0, pos))
+    .insert(Facing::new(std::f32::consts::FRAC_PI_2 * archetype.turn_rate))
+    .insert(AiPerception::new(visual_range, vision_cone_angle, pos))
+    .insert(AiMovement::new(archetype.move_speed, patrol_route.first().copied().unwrap_or(pos)))
     .insert(AiChaseBehavior{})
+    .insert(GuardBrain::new())
+    .insert(AiRadio::new(archetype.radio_range))
+    .insert(AiSearch{plan: None, target: pos})
+    .insert(archetype.clone())
     .insert(AiPerceptionDebugIndicator{})
     .insert(crate::lighting::DynamicLightBlocker{size: 25.0})
+    .insert(crate::level::LevelEntity)
     .id();
 
     let mesh = meshes.add(render_data.base_mesh.clone().unwrap());
@@ -187,24 +380,77 @@ pub fn spawn_enemy(commands: &mut Commands,
         visible: Visible { is_transparent: true, is_visible: true },
         ..Default::default()
     })
-    .insert(lighting::SpotLight::new(f32::to_radians(25.0), Color::RED, 500.0))
+    .insert(match archetype.light_inner_angle {
+        Some(inner_angle) => spotlight.with_inner_angle(f32::to_radians(inner_angle)),
+        None => spotlight,
+    })
     .insert(lighting::LightMeshData::default())
-    .insert(crate::visibility::VisChecker{radius: 250.0, visible: false})
+    // Kept up to date whenever any of the light could be on screen, or on the player
+    .insert(crate::visibility::VisChecker{radius: visual_range, visible: false})
     .id();
 
-    commands.entity(test_enemy).push_children(&[vision_spotlight]);
+    let state_text = commands.spawn_bundle(Text2dBundle {
+        text: Text::with_section(
+            "",
+            TextStyle {
+                font: asset_server.load("fonts/Roboto-Regular.ttf"),
+                font_size: 16.0,
+                color: Color::WHITE,
+            },
+            TextAlignment {
+                vertical: VerticalAlign::Center,
+                horizontal: HorizontalAlign::Center,
+            },
+        ),
+        transform: Transform::from_xyz(0.0, 50.0, 1.0),
+        ..Default::default()
+    })
+    .insert(GuardStateText)
+    .id();
+
+    let meter_back = commands.spawn_bundle(SpriteBundle {
+        material: materials.add(Color::rgba(0.0, 0.0, 0.0, 0.6).into()),
+        sprite: Sprite::new(Vec2::new(DETECTION_METER_WIDTH + 4.0, 8.0)),
+        transform: Transform::from_xyz(0.0, 32.0, 1.0),
+        visible: Visible { is_transparent: true, is_visible: false },
+        ..Default::default()
+    })
+    .insert(DetectionMeter{fill: false})
+    .id();
+
+    let meter_fill = commands.spawn_bundle(SpriteBundle {
+        material: materials.add(GuardState::Idle.color().into()),
+        sprite: Sprite::new(Vec2::new(0.0, 4.0)),
+        transform: Transform::from_xyz(0.0, 32.0, 1.1),
+        visible: Visible { is_transparent: true, is_visible: false },
+        ..Default::default()
+    })
+    .insert(DetectionMeter{fill: true})
+    .id();
+
+    commands.entity(test_enemy).push_children(&[vision_spotlight, state_text, meter_back, meter_fill]);
+
+    if !patrol_route.is_empty() {
+        commands.entity(test_enemy).insert(AiPatrol::new(patrol_route, archetype.patrol_pause));
+    }
 }
 
 pub fn ai_perception_system (
     query_pipeline: Res<QueryPipeline>,
     collider_query: QueryPipelineColliderComponentsQuery,
     rapier_config: Res<RapierConfiguration>,
-    time: Res<Time>,
     mut query: Query<(Entity, &mut AiPerception, &Transform, &Facing)>,
-    player_query: Query<(&player::PlayerMovement, &Transform, Entity)>
+    player_query: Query<(&player::PlayerMovement, &Transform, Entity, &RigidBodyVelocity)>,
+    smoke_query: Query<(&SmokeCloud, &Transform)>,
+    exposure: Res<LightExposure>,
+    mirrors: Res<Mirrors>,
 ) {
-    if let Ok((_player_movement, player_transform, player_entity)) = player_query.single() {
+    if let Ok((_player_movement, player_transform, player_entity, player_velocity)) = player_query.single() {
         let player_position = player_transform.translation;
+        let player_moving = player_velocity.linvel.norm() > 0.01;
+        let range_multiplier = if exposure.from_lamps >= LIT_THRESHOLD { LIT_RANGE_MULTIPLIER } else { 1.0 };
+        // The same squares that block the guards' light, so anything hidden from the light is hidden from the guard
+        let smoke = smoke_query.iter().map(|(cloud, transform)| (transform.translation.xy(), cloud.current_size())).collect::<Vec<(Vec2, f32)>>();
 
         for (percieve_entity, mut perciever, transform, facing) in query.iter_mut() {
             let collider_set = QueryPipelineColliderComponentsSet(&collider_query);
@@ -215,13 +461,14 @@ pub fn ai_perception_system (
                 .unwrap_or(-facing.forward());
 
             let angle = Vec2::angle_between(facing.forward(), dir_to_player).abs();
+            let visual_range = perciever.visual_range * range_multiplier;
 
             // Easy escape on cheap math checks
-            if angle <= perciever.vision_cone_angle && vec_to_player.length_squared() <= (perciever.visual_range * perciever.visual_range) {
+            if angle <= perciever.vision_cone_angle && vec_to_player.length_squared() <= (visual_range * visual_range) {
                 let ray = Ray::new(
                     point![transform.translation.x / rapier_config.scale, transform.translation.y / rapier_config.scale], 
                     vector![dir_to_player.x, dir_to_player.y]);
-                let max_toi = perciever.visual_range / rapier_config.scale;
+                let max_toi = visual_range / rapier_config.scale;
                 let solid = true;
                 let groups = InteractionGroups::all();
                 let filter_func = |handle: ColliderHandle| {
@@ -236,128 +483,460 @@ pub fn ai_perception_system (
                     let hit_point = ray.point_at(toi);
                     if let Ok((hit_entity, _coll_pos, _coll_shape, _coll_flags)) = collider_query.get(handle.entity()) {
                         // Bad way of telling if this is the player for now, since the player is the only ball
-                        if hit_entity == player_entity {
+                        if hit_entity == player_entity && !smoke_blocks_line(transform.translation.xy(), player_position.xy(), &smoke) {
                             perciever.can_see_target = true;
                             perciever.target_position = rapier_config.scale * Vec2::new(hit_point.x, hit_point.y);
                             perciever.target_direction = Vec2::angle_between(Vec2::new(0.0, 0.0), dir_to_player);
-                            perciever.last_seen_time = time.seconds_since_startup();
+                            if player_moving {
+                                perciever.target_heading = Vec2::new(player_velocity.linvel.x, player_velocity.linvel.y).normalize();
+                            }
+                            // Someone standing in shadow is harder to make out
+                            perciever.clarity = sight_clarity(
+                                vec_to_player.length(), visual_range,
+                                angle, perciever.vision_cone_angle,
+                                player_moving,
+                            ) * exposure.detection_factor();
                             continue;
                         }
                     }
                 }
             }
 
+            // Failing that the player might be seen in a mirror, by looking towards where their reflection appears
+            let guard_position = transform.translation.xy();
+            let first_hit = |from: Vec2, to: Vec2| {
+                let direction = (to - from).try_normalize()?;
+                let ray = Ray::new(point![from.x / rapier_config.scale, from.y / rapier_config.scale], vector![direction.x, direction.y]);
+                let filter_func = |handle: ColliderHandle| {
+                    handle.entity() != percieve_entity
+                };
+                let filter: Option<&dyn Fn(ColliderHandle) -> bool> = Some(&filter_func);
+                query_pipeline.cast_ray(&collider_set, &ray, from.distance(to) / rapier_config.scale, true, InteractionGroups::all(), filter)
+                    .map(|(handle, _toi)| handle.entity())
+            };
+            let reflection = mirrors.0.iter().find_map(|mirror| {
+                let image = mirror.reflect_point(player_position.xy());
+                let on_mirror = mirror.hit(guard_position, image)?;
+                let dir_to_image = (image - guard_position).try_normalize()?;
+                let angle = Vec2::angle_between(facing.forward(), dir_to_image).abs();
+                let distance = guard_position.distance(image);
+                if angle > perciever.vision_cone_angle || distance > visual_range {
+                    return None;
+                }
+
+                // Clear from the guard up to the mirror and from just off the mirror to the player
+                let off_mirror = on_mirror + mirror.normal;
+                let clear = first_hit(guard_position, on_mirror - dir_to_image).is_none()
+                    && first_hit(off_mirror, player_position.xy()) == Some(player_entity)
+                    && !smoke_blocks_line(guard_position, on_mirror, &smoke)
+                    && !smoke_blocks_line(on_mirror, player_position.xy(), &smoke);
+                if clear { Some((dir_to_image, distance, angle)) } else { None }
+            });
+
+            if let Some((dir_to_image, distance, angle)) = reflection {
+                perciever.can_see_target = true;
+                perciever.target_position = player_position.xy();
+                perciever.target_direction = Vec2::angle_between(Vec2::new(0.0, 0.0), dir_to_image);
+                if player_moving {
+                    perciever.target_heading = Vec2::new(player_velocity.linvel.x, player_velocity.linvel.y).normalize();
+                }
+                perciever.clarity = sight_clarity(
+                    distance, visual_range,
+                    angle, perciever.vision_cone_angle,
+                    player_moving,
+                ) * exposure.detection_factor();
+                continue;
+            }
+
             // If can see player we continued out of this iteration so if reached here we cannot see
             perciever.can_see_target = false;
+            perciever.clarity = 0.0;
+        }
+    }
+}
+
+pub fn ai_hearing_system(
+    nav: Res<NavGrid>,
+    mut noise_events: EventReader<NoiseEvent>,
+    mut query: Query<(&mut GuardBrain, &mut AiPerception, &mut AiMovement, &Transform)>,
+) {
+    for noise in noise_events.iter() {
+        for (mut brain, mut perciever, mut mover, transform) in query.iter_mut() {
+            let guard_position = transform.translation.xy();
+            // Cheap check first, pathfinding only for guards close enough to possibly hear it
+            if guard_position.distance(noise.position) > noise.radius || brain.state == GuardState::Alert {
+                continue;
+            }
+
+            let heard = match nav.find_path(guard_position, noise.position) {
+                Some(path) => {
+                    let mut path_length = 0.0;
+                    let mut last_point = guard_position;
+                    for point in path {
+                        path_length += last_point.distance(point);
+                        last_point = point;
+                    }
+                    path_length <= noise.radius * NOISE_PATH_FACTOR
+                }
+                None => false,
+            };
 
-            if perciever.last_seen_time == 0.0 {
-                perciever.last_seen_time = time.seconds_since_startup();
+            if heard && brain.investigate() {
+                perciever.target_position = noise.position;
+                perciever.target_heading = Vec2::default();
+                mover.move_to(noise.position);
             }
         }
     }
 }
 
-pub fn ai_movement_system(
-    rapier_parameters: Res<RapierConfiguration>,
-    time: Res<Time>,
-    task_pool: Res<ComputeTaskPool>,
-    levels: Res<Assets<level::LevelTiles>>,
-    mut query: Query<(&mut AiMovement, &mut RigidBodyVelocity, &mut Facing, &Transform)>,
-    level_query: Query<&Handle<level::LevelTiles>,>,
+pub fn ai_radio_system(
+    levels: Res<Assets<level_data::LevelTiles>>,
+    mut alert_events: EventReader<GuardAlertEvent>,
+    mut query: Query<(Entity, &mut GuardBrain, &mut AiPerception, &mut AiMovement, &AiRadio, &Transform)>,
+    level_query: Query<&Handle<level_data::LevelTiles>>,
 ) {
     if let Ok(level_handle) = level_query.single() {
-        if let Some(level) = levels.get(level_handle){
-            query.par_for_each_mut(&task_pool, 1, |(mut mover, mut rb_vel, mut facing, transform)| {
-                if !mover.move_to_target { 
-                    rb_vel.linvel = vector![0.0, 0.0];
-                    return; 
-                }
-            
-                if mover.current_path.is_empty()                                                    // No path
-                    || mover.path_index >= mover.current_path.len()                                 // Run out of path but still thinks need to move
-                    || mover.current_path.last().unwrap().distance(mover.target_position) > 60.0    // Last point in path is stale 
-                    // Safe to unwrap last since previous check covers the empty case
-                { 
-                    mover.path_index = 0;
-                    // path is stale or non-existent, need to request a new one
-                    if let Some(path) = level.get_path(transform.translation.xy(), mover.target_position) {
-                        mover.current_path = path;
-                        
+        if let Some(level) = levels.get(level_handle) {
+            for call in alert_events.iter() {
+                let range = match query.get_mut(call.caller) {
+                    Ok((_entity, _brain, _perciever, _mover, radio, _transform)) => radio.range,
+                    Err(_) => continue,
+                };
+
+                for (entity, mut brain, mut perciever, mut mover, _radio, transform) in query.iter_mut() {
+                    if entity == call.caller || brain.state == GuardState::Alert {
+                        continue;
                     }
-                    else {
-                        mover.current_path.clear();
-                        mover.move_to_target = false;
-                        return;
+
+                    let guard_position = transform.translation.xy();
+                    let distance = guard_position.distance(call.from);
+                    let heard = distance <= range
+                        || (distance <= perciever.visual_range && level.line_of_sight(guard_position, call.from));
+
+                    if heard && brain.investigate() {
+                        perciever.target_position = call.position;
+                        perciever.target_heading = call.heading;
+                        mover.move_to(call.position);
                     }
                 }
-    
-                let vec_to_target =  mover.target_position - transform.translation.xy();
-                let distance_to_target = vec_to_target.length();
-    
-                if distance_to_target < 60.0 {
-                    mover.move_to_target = false;
-                }
-                else {
+            }
+        }
+    }
+}
+
+pub fn ai_movement_system(
+    rapier_parameters: Res<RapierConfiguration>,
+    time: Res<Time>,
+    task_pool: Res<ComputeTaskPool>,
+    nav: Res<NavGrid>,
+    chase_field: Res<ChaseField>,
+    mut query: Query<(Entity, &mut AiMovement, &mut RigidBodyVelocity, &mut Facing, &Transform)>,
+    guard_query: Query<(Entity, &Transform), With<GuardBrain>>,
+    smoke_query: Query<(&SmokeCloud, &Transform)>,
+) {
+    let nav = &*nav;
+    let chase_field = &*chase_field;
+    let guards = guard_query.iter().map(|(entity, transform)| (entity, transform.translation.xy())).collect::<Vec<(Entity, Vec2)>>();
+    let smoke = smoke_query.iter().map(|(cloud, transform)| (transform.translation.xy(), 0.5 * cloud.current_size())).collect::<Vec<(Vec2, f32)>>();
+    let guards = &guards;
+    let smoke = &smoke;
+
+    query.par_for_each_mut(&task_pool, 1, |(entity, mut mover, mut rb_vel, mut facing, transform)| {
+        if !mover.move_to_target { 
+            rb_vel.linvel = vector![0.0, 0.0];
+            return; 
+        }
+
+        let position = transform.translation.xy();
+
+        // Enemies that can't move turn to look where they would have gone instead
+        if mover.base_speed <= 0.0 {
+            rb_vel.linvel = vector![0.0, 0.0];
+            match (mover.target_position - position).try_normalize() {
+                Some(direction) if direction.dot(facing.forward()) < 0.99 => facing.turn_towards_direction(direction, time.delta_seconds()),
+                _ => mover.move_to_target = false,
+            }
+            return;
+        }
+
+        // Guards chasing the player all follow the same flow field, everyone else finds their own path
+        let chase_step = match &chase_field.field {
+            Some(field) if mover.chasing => field.step_towards(nav, position, mover.target_position, GUARD_RADIUS),
+            _ => None,
+        };
+
+        if chase_step.is_none() && (
+            mover.current_path.is_empty()                                                       // No path
+            || mover.path_index >= mover.current_path.len()                                     // Run out of path but still thinks need to move
+            || mover.current_path.last().unwrap().distance(mover.target_position) > 60.0        // Last point in path is stale 
+            // Safe to unwrap last since previous check covers the empty case
+        ) { 
+            mover.path_index = 0;
+            // path is stale or non-existent, need to request a new one
+            if let Some(path) = nav.find_path(position, mover.target_position) {
+                mover.current_path = nav.smooth_path(position, &path, GUARD_RADIUS);
+            }
+            else {
+                mover.current_path.clear();
+                mover.move_to_target = false;
+                return;
+            }
+        }
+
+        let vec_to_target =  mover.target_position - position;
+        let distance_to_target = vec_to_target.length();
+
+        if distance_to_target < 60.0 {
+            mover.move_to_target = false;
+        }
+        else {
+            let next_point = match chase_step {
+                Some(step) => step,
+                None => {
                     // Move along path
                     let next_point = mover.current_path[mover.path_index];
-                    if transform.translation.xy().distance(next_point) < 10.0 {
+                    if position.distance(next_point) < 10.0 {
                         mover.path_index += 1;
                     }
-    
-                    let to_next_point = (next_point - transform.translation.xy()).normalize();
-                    //facing.set_forward(to_next_point);
-                    facing.turn_towards_direction(to_next_point, time.delta_seconds());
-                    let target_factor = to_next_point.normalize().dot(facing.forward()).clamp(0.0, 1.0).powi(3);
-    
-    
-                    let movement = facing.forward() * target_factor * (1.0 / rapier_parameters.scale) * mover.move_speed;
-                    rb_vel.linvel = vector![movement.x, movement.y];
+                    next_point
                 }
-            });
+            };
+
+            let to_next_point = (next_point - position).normalize();
+            //facing.set_forward(to_next_point);
+            facing.turn_towards_direction(to_next_point, time.delta_seconds());
+            let target_factor = to_next_point.normalize().dot(facing.forward()).clamp(0.0, 1.0).powi(3);
+
+
+            let others = guards.iter()
+                .filter(|(other, _position)| *other != entity)
+                .map(|(_other, position)| *position)
+                .collect::<Vec<Vec2>>();
+            let desired = facing.forward() * target_factor * mover.move_speed;
+            let movement = steer(position, desired, &others, smoke) / rapier_parameters.scale;
+            rb_vel.linvel = vector![movement.x, movement.y];
+        }
+    });
+}
+
+// Keeps a flow field towards the player's tile for alerted guards to follow, only rebuilt when the player
+// reaches another tile while someone is chasing them
+pub fn ai_chase_field_system(
+    nav: Res<NavGrid>,
+    mut chase_field: ResMut<ChaseField>,
+    mover_query: Query<&AiMovement>,
+    player_query: Query<&Transform, With<player::PlayerMovement>>,
+) {
+    // A rebuilt level may have moved the walls
+    if nav.is_changed() {
+        chase_field.field = None;
+    }
+
+    if !mover_query.iter().any(|mover| mover.chasing) {
+        return;
+    }
+
+    if let Ok(player_transform) = player_query.single() {
+        let player_cell = nav.world_to_grid(player_transform.translation.xy());
+        if chase_field.field.as_ref().map_or(true, |field| *field.goal() != player_cell) {
+            chase_field.field = Some(nav.flow_field(&player_cell));
         }
     }
 }
 
-pub fn ai_chase_behavior_system (
+// Moves each guard according to its state, the brain decides the state from how well the player is seen
+pub fn ai_guard_behavior_system (
     time: Res<Time>,
-    mut query: Query<(&mut AiMovement, &AiPerception, &mut Facing)>,
+    levels: Res<Assets<level_data::LevelTiles>>,
+    mut claims: ResMut<SearchClaims>,
+    mut alert_events: EventWriter<GuardAlertEvent>,
+    mut query: Query<(Entity, &mut GuardBrain, &mut AiMovement, &AiPerception, &mut Facing, &mut AiRadio, &mut AiSearch, &Archetype, &Transform, Option<&mut AiPatrol>)>,
+    level_query: Query<&Handle<level_data::LevelTiles>>,
 ) {
+    let level = match level_query.single().ok().and_then(|level_handle| levels.get(level_handle)) {
+        Some(level) => level,
+        None => return,
+    };
+
     let mut rng = rand::thread_rng();
-    for(mut mover, perciever, mut facing) in query.iter_mut() {
-        if perciever.can_see_target {
-            mover.move_to(perciever.target_position);
-            mover.move_speed = rng.gen_range(200.0..300.0);
-            facing.turn_rate = std::f32::consts::FRAC_PI_2;
-            
-        } 
-        else if !mover.is_moving(){
-            let time_since_seen = time.seconds_since_startup() - perciever.last_seen_time;
-            let search_rad_t = (time_since_seen / 90.0) as f32;
-            let search_rad = (search_rad_t * 1000.0) + 50.0;
-            mover.move_to(perciever.target_position + Vec2::new(rng.gen_range(-search_rad..search_rad), rng.gen_range(-search_rad..search_rad)));
-            mover.move_speed = rng.gen_range(50.0..120.0);
-            facing.turn_rate = std::f32::consts::FRAC_PI_3;
+    let delta = time.delta_seconds();
+
+    // Guards despawned by a level rebuild don't get to hold on to their cells
+    let guards = &query;
+    claims.0.retain(|entity, _cell| guards.get_component::<GuardBrain>(*entity).is_ok());
+
+    for (entity, mut brain, mut mover, perciever, mut facing, mut radio, mut search, archetype, transform, mut patrol) in query.iter_mut() {
+        let entered_state = brain.update(perciever.clarity, delta);
+        mover.move_speed = mover.base_speed * brain.state.speed_factor();
+        facing.turn_rate = brain.state.turn_rate() * archetype.turn_rate;
+
+        if brain.state != GuardState::Search {
+            claims.0.remove(&entity);
+        }
+
+        radio.cooldown -= delta;
+        if brain.state == GuardState::Alert && perciever.can_see_target && (entered_state || radio.cooldown <= 0.0) {
+            alert_events.send(GuardAlertEvent {
+                position: perciever.target_position,
+                heading: perciever.target_heading,
+                from: transform.translation.xy(),
+                caller: entity,
+            });
+            radio.cooldown = RADIO_INTERVAL;
+        }
+
+        match brain.state {
+            GuardState::Idle => {
+                if let Some(patrol) = &mut patrol {
+                    patrol.update(&mut mover, &mut facing, delta);
+                }
+                else if archetype.rotates {
+                    facing.turn(1.0, delta);
+                }
+                else if !mover.is_moving() {
+                    // Guards without a route mill about near their post
+                    let wander = 150.0;
+                    mover.move_to(perciever.home_point + Vec2::new(rng.gen_range(-wander..wander), rng.gen_range(-wander..wander)));
+                }
+            }
+            GuardState::Suspicious => {
+                // Creeps towards what it saw
+                if perciever.can_see_target || entered_state {
+                    mover.move_to(perciever.target_position);
+                }
+            }
+            GuardState::Alert => {
+                if perciever.can_see_target || entered_state {
+                    mover.chase(perciever.target_position);
+                }
+            }
+            GuardState::Search => {
+                let guard_cell = level.world_to_grid(transform.translation.xy());
+
+                if entered_state || search.plan.is_none() || search.target != perciever.target_position {
+                    // The last sighting can round to a wall tile when the player was hugging one, search from here instead
+                    let origin = if level.is_walkable_at(perciever.target_position) {
+                        level.world_to_grid(perciever.target_position)
+                    }
+                    else {
+                        guard_cell.clone()
+                    };
+                    search.plan = Some(SearchPlan::new(origin, perciever.target_heading, SEARCH_BUDGET));
+                    search.target = perciever.target_position;
+                }
+
+                if let Some(plan) = &mut search.plan {
+                    plan.mark_searched(&guard_cell);
+
+                    if !mover.is_moving() {
+                        let steps = SEARCH_START_STEPS + (brain.state_time * SEARCH_SPREAD_RATE) as u32;
+                        let others = claims.0.iter()
+                            .filter(|(other, _cell)| **other != entity)
+                            .map(|(_other, cell)| cell.clone())
+                            .collect::<Vec<level_data::GridPos>>();
+
+                        match plan.next_cell(level, steps, &others, &mut rng) {
+                            Some(cell) => {
+                                mover.move_to(level.grid_to_world(cell.clone()));
+                                claims.0.insert(entity, cell);
+                            }
+                            None => {
+                                brain.end_search();
+                                claims.0.remove(&entity);
+                                mover.move_to(return_point(patrol.as_deref_mut(), perciever.home_point));
+                            }
+                        }
+                    }
+                }
+            }
+            GuardState::Return => {
+                if entered_state {
+                    mover.move_to(return_point(patrol.as_deref_mut(), perciever.home_point));
+                }
+                else if !mover.is_moving() {
+                    brain.returned();
+                }
+            }
         }
     }
 }
 
+// Where a guard heads once it gives up, picking its patrol back up if it has one
+fn return_point(patrol: Option<&mut AiPatrol>, home_point: Vec2) -> Vec2 {
+    match patrol {
+        Some(patrol) => patrol.resume(),
+        None => home_point,
+    }
+}
+
 pub fn ai_perception_debug_system (
     mut materials: ResMut<Assets<ColorMaterial>>,
-    mut query: Query<(&AiPerception, &AiPerceptionDebugIndicator, &mut Handle<ColorMaterial>)>,
+    mut query: Query<(&GuardBrain, &Archetype, &AiPerceptionDebugIndicator, &mut Handle<ColorMaterial>)>,
     mut light_query: Query<(&Parent, &mut lighting::SpotLight)>
 ) {
-    let see_color =Color::rgb(0.8,0.35,0.2);
-    let cant_color = Color::rgb(0.2,0.7,0.8);
-
-    for (perciever, _indicator, mat_handle) in query.iter_mut() {
+    for (brain, _archetype, _indicator, mat_handle) in query.iter_mut() {
         if let Some(mut color_mat) = materials.get_mut(mat_handle.id) {
-            color_mat.color = if perciever.can_see_target {see_color} else {cant_color};
+            color_mat.color = brain.state.color();
         }
     } 
 
     for (parent, mut spotlight) in light_query.iter_mut() {
-        if let Ok((perciever, _indicator, _mat_handle)) = query.get_mut(parent.0) {
-            spotlight.color = if perciever.can_see_target {see_color} else {cant_color};
+        if let Ok((brain, archetype, _indicator, _mat_handle)) = query.get_mut(parent.0) {
+            spotlight.color = if brain.state == GuardState::Idle {
+                let [r, g, b] = archetype.light_color;
+                Color::rgb(r, g, b)
+            }
+            else {
+                brain.state.color()
+            };
         }
     }
-}
\ No newline at end of file
+}
+
+pub fn ai_state_text_system (
+    keyboard_input: Res<Input<KeyCode>>,
+    mut guard_debug: ResMut<GuardDebug>,
+    brain_query: Query<&GuardBrain>,
+    mut text_query: Query<(&Parent, &mut Text, &mut Visible), With<GuardStateText>>,
+) {
+    if keyboard_input.just_pressed(KeyCode::F3) {
+        guard_debug.show_state = !guard_debug.show_state;
+    }
+
+    for (parent, mut text, mut visible) in text_query.iter_mut() {
+        visible.is_visible = guard_debug.show_state;
+        if !guard_debug.show_state { continue; }
+
+        if let Ok(brain) = brain_query.get(parent.0) {
+            let label = format!("{:?} {:.0}%", brain.state, brain.suspicion * 100.0);
+            // Only touch the text when it changes so it isn't laid out again every frame
+            if text.sections[0].value != label {
+                text.sections[0].value = label;
+                text.sections[0].style.color = brain.state.color();
+            }
+        }
+    }
+}
+
+pub fn ai_detection_meter_system (
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    brain_query: Query<&GuardBrain>,
+    mut meter_query: Query<(&Parent, &DetectionMeter, &mut Sprite, &mut Transform, &mut Visible, &Handle<ColorMaterial>)>,
+) {
+    for (parent, meter, mut sprite, mut transform, mut visible, mat_handle) in meter_query.iter_mut() {
+        if let Ok(brain) = brain_query.get(parent.0) {
+            // Only shown once the guard has noticed something
+            visible.is_visible = brain.suspicion > 0.0;
+
+            if meter.fill && visible.is_visible {
+                sprite.size.x = DETECTION_METER_WIDTH * brain.suspicion;
+                // Keep the left edge in place so the bar fills left to right
+                transform.translation.x = -0.5 * DETECTION_METER_WIDTH * (1.0 - brain.suspicion);
+                if let Some(mut color_mat) = materials.get_mut(mat_handle.id) {
+                    color_mat.color = brain.state.color();
+                }
+            }
+        }
+    }
+}
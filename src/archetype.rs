@@ -0,0 +1,159 @@
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::level_data::EnemyParams;
+
+// Kind of enemy placed when a level doesn't ask for one
+pub const DEFAULT_ARCHETYPE: &str = "guard";
+
+// Everything that sets one kind of enemy apart. These are read from assets/enemies.archetypes so new kinds
+// can be added without a code change, anything left out of an entry is the same as a plain guard.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Archetype {
+    pub visual_range: f32,
+    // In degrees, to either side of where it is facing
+    pub vision_cone_angle: f32,
+    // 0 for enemies that never leave their post, they turn to look instead
+    pub move_speed: f32,
+    // Seconds spent looking around at each patrol waypoint
+    pub patrol_pause: f32,
+    pub radio_range: f32,
+    // Multiplier on how fast it turns in every state
+    pub turn_rate: f32,
+    // Keeps turning on the spot when it has nothing else to do, instead of wandering
+    pub rotates: bool,
+    // Width of the sprite in pixels
+    pub size: f32,
+    // Spotlight color while it hasn't noticed anything, the state colors take over once it has
+    pub light_color: [f32; 3],
+    // In degrees, the spotlight is at full brightness inside this and fades out to the edge of the vision cone.
+    // None leaves it to the light.
+    pub light_inner_angle: Option<f32>,
+    // Width of the soft edge on the spotlight's shadows
+    pub light_softness: f32,
+}
+
+impl Default for Archetype {
+    fn default() -> Archetype {
+        Archetype {
+            visual_range: 500.0,
+            vision_cone_angle: 25.0,
+            move_speed: 150.0,
+            patrol_pause: 2.0,
+            radio_range: 600.0,
+            turn_rate: 1.0,
+            rotates: false,
+            size: 40.0,
+            light_color: [1.0, 0.0, 0.0],
+            light_inner_angle: None,
+            light_softness: 8.0,
+        }
+    }
+}
+
+impl Archetype {
+    // Per level tweaks from the level file win over the archetype
+    pub fn with_params(&self, params: &EnemyParams) -> Archetype {
+        Archetype {
+            visual_range: params.visual_range.unwrap_or(self.visual_range),
+            vision_cone_angle: params.vision_cone_angle.unwrap_or(self.vision_cone_angle),
+            move_speed: params.move_speed.unwrap_or(self.move_speed),
+            patrol_pause: params.patrol_pause.unwrap_or(self.patrol_pause),
+            radio_range: params.radio_range.unwrap_or(self.radio_range),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(TypeUuid, Default)]
+#[uuid = "0d6b1c52-3a5e-4f0e-9a43-6f4f1d2b8c71"]
+pub struct EnemyArchetypes {
+    archetypes: HashMap<String, Archetype>,
+}
+
+impl EnemyArchetypes {
+    pub fn parse(text: &str) -> Result<EnemyArchetypes, anyhow::Error> {
+        let archetypes = ron::de::from_str::<HashMap<String, Archetype>>(text)?;
+        return Ok(EnemyArchetypes { archetypes });
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.archetypes.contains_key(name)
+    }
+
+    // What to spawn for an enemy tile, an unknown archetype is reported and spawned as a plain guard
+    pub fn resolve(&self, params: &EnemyParams) -> Archetype {
+        let name = params.archetype.as_deref().unwrap_or(DEFAULT_ARCHETYPE);
+        let archetype = match self.archetypes.get(name) {
+            Some(archetype) => archetype.clone(),
+            None => {
+                if params.archetype.is_some() {
+                    println!("Unknown enemy archetype '{}', spawning a guard instead", name);
+                }
+                Archetype::default()
+            }
+        };
+        return archetype.with_params(params);
+    }
+}
+
+impl AssetLoader for EnemyArchetypes {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let archetypes = EnemyArchetypes::parse(std::str::from_utf8(bytes)?)?;
+            load_context.set_default_asset(LoadedAsset::new(archetypes));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["archetypes"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shipped_archetypes_parse() {
+        let archetypes = EnemyArchetypes::parse(include_str!("../assets/enemies.archetypes")).unwrap();
+        for name in [DEFAULT_ARCHETYPE, "hound", "camera", "sentry"].iter() {
+            assert!(archetypes.contains(name), "Missing archetype {}", name);
+        }
+        assert_eq!(archetypes.archetypes[DEFAULT_ARCHETYPE], Archetype::default());
+    }
+
+    #[test]
+    fn test_resolve_archetype() {
+        let archetypes = EnemyArchetypes::parse("{ \"hound\": (move_speed: 250.0, size: 30.0) }").unwrap();
+
+        let hound = archetypes.resolve(&EnemyParams { archetype: Some("hound".to_string()), ..Default::default() });
+        assert_eq!(hound.move_speed, 250.0);
+        assert_eq!(hound.size, 30.0);
+        assert_eq!(hound.visual_range, Archetype::default().visual_range, "Unset fields are the same as a guard");
+
+        // Level overrides still apply on top
+        let tweaked = archetypes.resolve(&EnemyParams { archetype: Some("hound".to_string()), move_speed: Some(100.0), ..Default::default() });
+        assert_eq!(tweaked.move_speed, 100.0);
+        assert_eq!(tweaked.size, 30.0);
+
+        assert_eq!(archetypes.resolve(&EnemyParams::default()), Archetype::default());
+        assert_eq!(archetypes.resolve(&EnemyParams { archetype: Some("dragon".to_string()), ..Default::default() }), Archetype::default());
+    }
+
+    #[test]
+    fn test_unknown_field_is_an_error() {
+        assert!(EnemyArchetypes::parse("{ \"hound\": (speed: 250.0) }").is_err());
+    }
+}
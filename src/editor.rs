@@ -0,0 +1,293 @@
+use bevy::prelude::*;
+
+use crate::gamestate::{CurrentLevel, GameState};
+use crate::level_data::{check_level, GridPos, LevelTiles, TileValue};
+
+pub struct EditorPlugin;
+
+impl Plugin for EditorPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app
+            .insert_resource(EditorBrush{tile: TileValue::Wall})
+            .insert_resource(EditorStroke::default())
+            .add_system_set(SystemSet::on_enter(GameState::Editor)
+                .with_system(editor_setup.system())
+            )
+            .add_system_set(SystemSet::on_exit(GameState::Editor)
+                .with_system(editor_finish_stroke.system())
+            )
+            .add_system_set(SystemSet::on_update(GameState::Editor)
+                .with_system(editor_keyboard.system())
+                .with_system(editor_camera_system.system())
+                .with_system(editor_paint_system.system())
+                .with_system(editor_text_system.system())
+            )
+            .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(playtest_keyboard.system())
+            )
+        ;
+    }
+}
+
+pub struct EditorBrush {
+    pub tile: TileValue,
+}
+
+// Tiles painted since the mouse button went down. Every change to the level asset rebuilds the whole level, so they're
+// only shown as previews until the button is released and then applied in one go.
+#[derive(Default)]
+pub struct EditorStroke {
+    tiles: Vec<(GridPos, TileValue)>,
+}
+
+struct StrokePreview;
+
+const PREVIEW_PAINT_COLOR: Color = Color::rgba(1.0, 1.0, 1.0, 0.4);
+const PREVIEW_ERASE_COLOR: Color = Color::rgba(1.0, 0.2, 0.2, 0.4);
+
+struct EditorText;
+
+const BRUSHES: [(KeyCode, TileValue); 7] = [
+    (KeyCode::Key1, TileValue::Empty),
+    (KeyCode::Key2, TileValue::Wall),
+    (KeyCode::Key3, TileValue::Pickup),
+    (KeyCode::Key4, TileValue::Player),
+    (KeyCode::Key5, TileValue::Enemy),
+    (KeyCode::Key6, TileValue::Lamp),
+    (KeyCode::Key7, TileValue::Mirror),
+];
+
+fn editor_setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut current_level: ResMut<CurrentLevel>,
+) {
+    // Coming back from a play-test keeps editing the same, possibly unsaved, level
+    let level_handle = match &current_level.editing {
+        Some(handle) => handle.clone(),
+        None => {
+            let level_path = "levels/".to_string() + &current_level.name + ".level";
+            println!("Editing level: {}", level_path);
+            let handle: Handle<LevelTiles> = asset_server.load(&level_path as &str);
+            current_level.editing = Some(handle.clone());
+            handle
+        }
+    };
+    crate::level::spawn_level(&mut commands, level_handle);
+
+    commands.spawn_bundle(TextBundle {
+        text: Text {
+            sections: vec![
+                TextSection {
+                    value: "Editing: ".to_string() + &current_level.name,
+                    style: TextStyle {
+                        font: asset_server.load("fonts/Roboto-Regular.ttf"),
+                        font_size: 30.0,
+                        color: Color::rgb(1.0, 0.7, 0.1),
+                    },
+                },
+                TextSection {
+                    value: "".to_string(),
+                    style: TextStyle {
+                        font: asset_server.load("fonts/Roboto-Regular.ttf"),
+                        font_size: 30.0,
+                        color: Color::rgb(1.0, 0.7, 0.1),
+                    },
+                },
+                TextSection {
+                    value: "\n[1-7] Empty/Wall/Card/Player/Guard/Lamp/Mirror\n[Left Mouse] paint, [Right Mouse] erase\n[WASD] to move\n[Enter] to play-test, [F2] to come back\n[F5] to save\n[Esc] to return to title".to_string(),
+                    style: TextStyle {
+                        font: asset_server.load("fonts/Roboto-Regular.ttf"),
+                        font_size: 20.0,
+                        color: Color::rgb(1.0, 1.0, 1.0),
+                    },
+                },
+            ],
+            ..Default::default()
+        },
+        style: Style {
+            position_type: PositionType::Absolute,
+            position: Rect {
+                top: Val::Px(5.0),
+                left: Val::Px(5.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .insert(EditorText);
+}
+
+fn editor_keyboard(
+    mut state: ResMut<State<GameState>>,
+    mut brush: ResMut<EditorBrush>,
+    mut current_level: ResMut<CurrentLevel>,
+    mut score: ResMut<crate::gamestate::Score>,
+    levels: Res<Assets<LevelTiles>>,
+    keyboard_input: Res<Input<KeyCode>>,
+) {
+    for (key, tile) in BRUSHES.iter() {
+        if keyboard_input.just_pressed(*key) {
+            brush.tile = tile.clone();
+        }
+    }
+
+    if keyboard_input.just_pressed(KeyCode::F5) {
+        if let Some(level) = current_level.editing.as_ref().and_then(|handle| levels.get(handle)) {
+            save_level(&current_level.name, level);
+        }
+    }
+
+    if keyboard_input.just_pressed(KeyCode::Return) {
+        score.reset();
+        state.set(GameState::Playing).unwrap();
+    }
+
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        // Unsaved changes are thrown away
+        current_level.editing = None;
+        state.set(GameState::Startup).unwrap();
+    }
+}
+
+// Writes the level over its file, the file watcher then reloads it like any other change
+fn save_level(name: &str, level: &LevelTiles) {
+    let path = format!("assets/levels/{}.level", name);
+    let text = level.to_level_string();
+
+    let check = check_level(&text);
+    if !check.diagnostics.is_empty() {
+        println!("{}", check.report(&path));
+    }
+
+    match std::fs::write(&path, text) {
+        Ok(()) => println!("Saved level to {}", path),
+        Err(err) => println!("Couldn't save level to {}: {}", path, err),
+    }
+}
+
+fn playtest_keyboard(
+    mut state: ResMut<State<GameState>>,
+    current_level: Res<CurrentLevel>,
+    keyboard_input: Res<Input<KeyCode>>,
+) {
+    if current_level.editing.is_some() && keyboard_input.just_pressed(KeyCode::F2) {
+        state.set(GameState::Editor).unwrap();
+    }
+}
+
+fn editor_camera_system(
+    time: Res<Time>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut camera_query: Query<&mut Transform, With<crate::MainCam>>,
+) {
+    let pan_speed = 600.0;
+
+    if let Ok(mut camera_transform) = camera_query.single_mut() {
+        let mut movement = Vec2::default();
+        if keyboard_input.pressed(KeyCode::W) {
+            movement.y += 1.0;
+        }
+        if keyboard_input.pressed(KeyCode::S) {
+            movement.y -= 1.0;
+        }
+        if keyboard_input.pressed(KeyCode::A) {
+            movement.x -= 1.0;
+        }
+        if keyboard_input.pressed(KeyCode::D) {
+            movement.x += 1.0;
+        }
+
+        if movement != Vec2::default() {
+            movement = movement.normalize() * pan_speed * time.delta_seconds();
+        }
+        camera_transform.translation += movement.extend(0.0);
+    }
+}
+
+fn editor_paint_system(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut stroke: ResMut<EditorStroke>,
+    mouse_input: Res<Input<MouseButton>>,
+    windows: Res<Windows>,
+    brush: Res<EditorBrush>,
+    current_level: Res<CurrentLevel>,
+    mut levels: ResMut<Assets<LevelTiles>>,
+    camera_query: Query<&Transform, With<crate::MainCam>>,
+    preview_query: Query<Entity, With<StrokePreview>>,
+) {
+    let tile = if mouse_input.pressed(MouseButton::Left) {
+        brush.tile.clone()
+    }
+    else if mouse_input.pressed(MouseButton::Right) {
+        TileValue::Empty
+    }
+    else {
+        if !stroke.tiles.is_empty() {
+            apply_stroke(&mut stroke, &current_level, &mut levels);
+            for preview in preview_query.iter() {
+                commands.entity(preview).despawn();
+            }
+        }
+        return;
+    };
+
+    let level = match current_level.editing.as_ref().and_then(|handle| levels.get(handle)) {
+        Some(level) => level,
+        None => return,
+    };
+
+    if let (Some(window), Ok(camera_transform)) = (windows.get_primary(), camera_query.single()) {
+        if let Some(cursor) = window.cursor_position() {
+            // Cursor is measured from the bottom left of the window, the camera is at its center
+            let world_pos = cursor - 0.5 * Vec2::new(window.width(), window.height()) + camera_transform.translation.truncate();
+            let grid_pos = level.world_to_grid(world_pos);
+            let already_painted = stroke.tiles.iter().any(|(pos, _tile)| *pos == grid_pos);
+            if !level.in_bounds(&grid_pos) || already_painted || level.get_tile(&grid_pos) == tile {
+                return;
+            }
+
+            let color = if tile == TileValue::Empty { PREVIEW_ERASE_COLOR } else { PREVIEW_PAINT_COLOR };
+            commands.spawn_bundle(SpriteBundle {
+                material: materials.add(color.into()),
+                sprite: Sprite::new(Vec2::new(level.tile_size, level.tile_size)),
+                transform: Transform::from_translation(level.grid_to_world(grid_pos.clone()).extend(1.0)),
+                ..Default::default()
+            })
+            .insert(StrokePreview);
+            stroke.tiles.push((grid_pos, tile));
+        }
+    }
+}
+
+// Leaving the editor mid stroke keeps what was painted, the previews go with everything else in the teardown
+fn editor_finish_stroke(
+    mut stroke: ResMut<EditorStroke>,
+    current_level: Res<CurrentLevel>,
+    mut levels: ResMut<Assets<LevelTiles>>,
+) {
+    if !stroke.tiles.is_empty() {
+        apply_stroke(&mut stroke, &current_level, &mut levels);
+    }
+}
+
+// One modification for the whole stroke, so the level only gets rebuilt once
+fn apply_stroke(stroke: &mut EditorStroke, current_level: &CurrentLevel, levels: &mut Assets<LevelTiles>) {
+    if let Some(level) = current_level.editing.as_ref().and_then(|handle| levels.get_mut(handle)) {
+        for (grid_pos, tile) in stroke.tiles.iter() {
+            level.set_tile(grid_pos, tile.clone());
+        }
+    }
+    stroke.tiles.clear();
+}
+
+fn editor_text_system(
+    brush: Res<EditorBrush>,
+    mut query: Query<&mut Text, With<EditorText>>,
+) {
+    for mut text in query.iter_mut() {
+        text.sections[1].value = format!("\nBrush: {:?}", brush.tile);
+    }
+}
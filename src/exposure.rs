@@ -0,0 +1,96 @@
+use bevy::prelude::*;
+
+// How lit the player is this frame, worked out by lighting::light_exposure_system with the same falloff as the light shader
+pub struct LightExposure {
+    // Ambient light plus every light falling on the player, from 0 in darkness to 1 when fully lit
+    pub level: f32,
+    // The part of that coming from lamps, a player standing in lamp light can be seen from further away
+    pub from_lamps: f32,
+}
+
+// Even in pitch darkness guards have some chance of noticing someone right in front of them
+const DARK_DETECTION: f32 = 0.3;
+
+const GEM_DARK: [f32; 3] = [0.05, 0.12, 0.08];
+const GEM_LIT: [f32; 3] = [0.45, 1.0, 0.6];
+
+impl Default for LightExposure {
+    // Fully lit until the lights have been sampled, so nothing changes for a level without any
+    fn default() -> LightExposure {
+        LightExposure { level: 1.0, from_lamps: 0.0 }
+    }
+}
+
+impl LightExposure {
+    // Multiplier on how clearly guards see the player
+    pub fn detection_factor(&self) -> f32 {
+        DARK_DETECTION + (1.0 - DARK_DETECTION) * self.level.clamp(0.0, 1.0)
+    }
+
+    // Color of the HUD gem, glowing brighter the more the player can be seen
+    pub fn gem_color(&self) -> Color {
+        let level = self.level.clamp(0.0, 1.0);
+        let mix = |channel: usize| GEM_DARK[channel] + (GEM_LIT[channel] - GEM_DARK[channel]) * level;
+        Color::rgb(mix(0), mix(1), mix(2))
+    }
+}
+
+// How brightly a light falls on a point it can reach, the same as FRAGMENT_SHADER. The angle is from the light's facing
+// in radians, lights without a cone have their inner and outer angles the same and wide enough to cover everything.
+pub fn light_falloff(distance: f32, reach: f32, angle: f32, inner_angle: f32, outer_angle: f32) -> f32 {
+    let angle_falloff = if inner_angle < outer_angle {
+        1.0 - smoothstep(inner_angle, outer_angle, angle)
+    }
+    else if angle <= outer_angle {
+        1.0
+    }
+    else {
+        0.0
+    };
+    let power = (1.0 - distance / reach).clamp(0.0, 1.0);
+    return power.powi(3) * angle_falloff;
+}
+
+// A light is as strong as its brightest color channel
+pub fn light_strength(color: Color) -> f32 {
+    color.r().max(color.g()).max(color.b())
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_light_falloff() {
+        assert_eq!(light_falloff(0.0, 100.0, 0.0, 0.3, 0.5), 1.0);
+        assert!((light_falloff(50.0, 100.0, 0.0, 0.3, 0.5) - 0.125).abs() < 0.0001);
+        assert_eq!(light_falloff(150.0, 100.0, 0.0, 0.3, 0.5), 0.0, "Out of reach");
+
+        // Fades across the edge of the cone
+        let edge = light_falloff(0.0, 100.0, 0.4, 0.3, 0.5);
+        assert!(edge > 0.0 && edge < 1.0);
+        assert!((edge - 0.5).abs() < 0.0001, "Halfway across the edge is half as bright");
+        assert_eq!(light_falloff(0.0, 100.0, 0.6, 0.3, 0.5), 0.0, "Outside the cone");
+
+        // Hard edged cone
+        assert_eq!(light_falloff(0.0, 100.0, 0.49, 0.5, 0.5), 1.0);
+        assert_eq!(light_falloff(0.0, 100.0, 0.51, 0.5, 0.5), 0.0);
+    }
+
+    #[test]
+    fn test_darkness_hides_the_player() {
+        let dark = LightExposure { level: 0.0, from_lamps: 0.0 };
+        let lit = LightExposure { level: 1.0, from_lamps: 0.0 };
+        assert_eq!(lit.detection_factor(), 1.0);
+        assert!(dark.detection_factor() < 0.5 && dark.detection_factor() > 0.0, "Still seen up close in the dark");
+        assert!(LightExposure { level: 3.0, from_lamps: 3.0 }.detection_factor() <= 1.0);
+
+        assert!(light_strength(lit.gem_color()) > light_strength(dark.gem_color()));
+        assert_eq!(light_strength(Color::rgb(0.2, 0.9, 0.4)), 0.9);
+    }
+}
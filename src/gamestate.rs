@@ -1,26 +1,104 @@
 use bevy::prelude::*;
 use bevy::app::AppExit;
 
+use crate::level_data::LevelTiles;
+
 #[derive(Clone, Eq, PartialEq, Debug, Hash)]
 pub enum GameState {
     Startup,
     Playing,
     GameOver,
+    LevelComplete,
+    Editor,
 }
 
 pub struct Score {
     pub value: i32,
     pub max: i32,
+    // Seconds spent on the current attempt at the level
+    pub time: f32,
+}
+
+impl Score {
+    pub fn reset(&mut self) {
+        self.value = 0;
+        self.time = 0.0;
+    }
 }
 
 pub struct CurrentLevel {
     pub name: String,
+    // Level named in the header of the current level file, empty if this is the last level
+    pub next: String,
+    // Level the campaign restarts from once the last level is beaten
+    pub first: String,
+    pub title: String,
+    pub par_time: Option<f32>,
+    // Set when playing an endless run of generated levels instead of level files
+    pub generated: Option<GeneratedLevel>,
+    // Level open in the editor, play-testing uses this instead of loading the file again.
+    // Holding the handle here keeps unsaved changes alive while the editor's entities are torn down.
+    pub editing: Option<Handle<LevelTiles>>,
+}
+
+pub struct GeneratedLevel {
+    pub seed: u64,
+    // How many generated levels have been beaten so far in this run
+    pub depth: u32,
+}
+
+impl CurrentLevel {
+    pub fn new(name: &str) -> CurrentLevel {
+        CurrentLevel {
+            name: name.to_string(),
+            next: "".to_string(),
+            first: name.to_string(),
+            title: "".to_string(),
+            par_time: None,
+            generated: None,
+            editing: None,
+        }
+    }
+
+    pub fn is_last_level(&self) -> bool {
+        self.generated.is_none() && self.editing.is_none() && self.next.is_empty()
+    }
 }
 
 pub struct PerfDebug {
     pub spotlight_updates: i32,
 }
 
+pub fn level_timer_system(time: Res<Time>, mut score: ResMut<Score>) {
+    score.time += time.delta_seconds();
+}
+
+pub fn title_keyboard(
+    mut state: ResMut<State<GameState>>, 
+    mut exit: EventWriter<AppExit>, 
+    mut current_level: ResMut<CurrentLevel>,
+    keyboard_input: Res<Input<KeyCode>>
+) {
+    if keyboard_input.just_pressed(KeyCode::Space) {
+        current_level.generated = None;
+        current_level.editing = None;
+        state.set(GameState::Playing).unwrap();
+    }
+    if keyboard_input.just_pressed(KeyCode::G) {
+        current_level.generated = Some(GeneratedLevel { seed: rand::random(), depth: 0 });
+        current_level.editing = None;
+        state.set(GameState::Playing).unwrap();
+    }
+    if keyboard_input.just_pressed(KeyCode::E) {
+        current_level.generated = None;
+        current_level.editing = None;
+        state.set(GameState::Editor).unwrap();
+    }
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        exit.send(AppExit);
+    }
+}
+
 pub fn startgame_keyboard(mut state: ResMut<State<GameState>>, mut exit: EventWriter<AppExit>, keyboard_input: Res<Input<KeyCode>>) {
     if keyboard_input.just_pressed(KeyCode::Space) {
         state.set(GameState::Playing).unwrap();
@@ -28,4 +106,35 @@ pub fn startgame_keyboard(mut state: ResMut<State<GameState>>, mut exit: EventWr
     if keyboard_input.just_pressed(KeyCode::Escape) {
         exit.send(AppExit);
     }
+}
+
+pub fn level_complete_keyboard(
+    mut state: ResMut<State<GameState>>,
+    mut exit: EventWriter<AppExit>,
+    mut score: ResMut<Score>,
+    mut current_level: ResMut<CurrentLevel>,
+    keyboard_input: Res<Input<KeyCode>>
+) {
+    if keyboard_input.just_pressed(KeyCode::Space) {
+        score.reset();
+        if current_level.editing.is_some() {
+            state.set(GameState::Editor).unwrap();
+        }
+        else if let Some(generated) = &mut current_level.generated {
+            generated.seed = generated.seed.wrapping_add(1);
+            generated.depth += 1;
+            state.set(GameState::Playing).unwrap();
+        }
+        else if current_level.is_last_level() {
+            current_level.name = current_level.first.clone();
+            state.set(GameState::Startup).unwrap();
+        }
+        else {
+            current_level.name = current_level.next.clone();
+            state.set(GameState::Playing).unwrap();
+        }
+    }
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        exit.send(AppExit);
+    }
 }
\ No newline at end of file
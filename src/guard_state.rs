@@ -0,0 +1,287 @@
+use bevy::prelude::*;
+
+// How fast suspicion builds while the player is in full view, per second
+const SUSPICION_RISE_RATE: f32 = 1.0;
+// Suspicion builds this much faster for a guard that is already searching
+const SEARCH_RISE_MULTIPLIER: f32 = 3.0;
+// How fast suspicion fades once the player is out of sight, per second
+const SUSPICION_DECAY_RATE: f32 = 0.25;
+// Seconds an alerted guard keeps chasing the last sighting before it starts searching
+const ALERT_LOSE_TIME: f32 = 2.0;
+// Seconds of searching without another sighting before a guard gives up and goes back, even if it still
+// has places left to look
+const SEARCH_TIME: f32 = 20.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GuardState {
+    // Patrolling or standing at its post
+    Idle,
+    // Caught a glimpse, stops to look towards it while suspicion builds
+    Suspicious,
+    // Knows where the player is and is chasing them
+    Alert,
+    // Lost the player, looking around where they were last seen
+    Search,
+    // Gave up, heading back to its post or patrol route
+    Return,
+}
+
+impl GuardState {
+    // Multiplier on the guard's base move speed
+    pub fn speed_factor(&self) -> f32 {
+        match self {
+            GuardState::Idle => 0.6,
+            GuardState::Suspicious => 0.4,
+            GuardState::Alert => 1.66,
+            GuardState::Search => 0.55,
+            GuardState::Return => 0.6,
+        }
+    }
+
+    // Radians per second
+    pub fn turn_rate(&self) -> f32 {
+        match self {
+            GuardState::Suspicious | GuardState::Alert => std::f32::consts::FRAC_PI_2,
+            _ => std::f32::consts::FRAC_PI_3,
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        match self {
+            GuardState::Idle => Color::rgb(0.2, 0.7, 0.8),
+            GuardState::Suspicious => Color::rgb(0.9, 0.8, 0.2),
+            GuardState::Alert => Color::rgb(0.8, 0.35, 0.2),
+            GuardState::Search => Color::rgb(0.9, 0.55, 0.2),
+            GuardState::Return => Color::rgb(0.4, 0.6, 0.9),
+        }
+    }
+}
+
+// Decides what a guard is doing from what it can see, kept separate from the ECS so it can be tested on its own
+pub struct GuardBrain {
+    pub state: GuardState,
+    // Detection meter, builds from 0 to 1 while the player is seen and drains while they aren't.
+    // A guard only gives chase once it is full.
+    pub suspicion: f32,
+    // Seconds since the last state change
+    pub state_time: f32,
+    // Seconds since the player was last seen
+    unseen_time: f32,
+}
+
+impl GuardBrain {
+    pub fn new() -> GuardBrain {
+        GuardBrain {
+            state: GuardState::Idle,
+            suspicion: 0.0,
+            state_time: 0.0,
+            unseen_time: 0.0,
+        }
+    }
+
+    // Clarity is how well the player can be seen this frame, 0 when out of sight up to 1 when close and in plain view.
+    // Returns true if the state changed.
+    pub fn update(&mut self, clarity: f32, delta: f32) -> bool {
+        let seen = clarity > 0.0;
+        if seen {
+            let rate = if self.state == GuardState::Search { SUSPICION_RISE_RATE * SEARCH_RISE_MULTIPLIER } else { SUSPICION_RISE_RATE };
+            self.suspicion = (self.suspicion + clarity * rate * delta).min(1.0);
+            self.unseen_time = 0.0;
+        }
+        else {
+            self.suspicion = (self.suspicion - SUSPICION_DECAY_RATE * delta).max(0.0);
+            self.unseen_time += delta;
+        }
+        self.state_time += delta;
+
+        let next = match self.state {
+            GuardState::Idle | GuardState::Return if seen => GuardState::Suspicious,
+            GuardState::Suspicious | GuardState::Search if self.suspicion >= 1.0 => GuardState::Alert,
+            GuardState::Suspicious if self.suspicion <= 0.0 => GuardState::Return,
+            GuardState::Alert if self.unseen_time >= ALERT_LOSE_TIME => GuardState::Search,
+            GuardState::Search if self.state_time >= SEARCH_TIME => GuardState::Return,
+            state => state,
+        };
+
+        self.set_state(next)
+    }
+
+    // Heard a noise or another guard calling out a sighting, returns false if the guard is too busy chasing to care
+    pub fn investigate(&mut self) -> bool {
+        if self.state == GuardState::Alert {
+            return false;
+        }
+        // Searching again restarts the search timer around the new position
+        self.set_state(GuardState::Search);
+        self.state_time = 0.0;
+        return true;
+    }
+
+    // Called once a searching guard has run out of places to look
+    pub fn end_search(&mut self) -> bool {
+        if self.state == GuardState::Search { self.set_state(GuardState::Return) } else { false }
+    }
+
+    // Called once a returning guard is back at its post
+    pub fn returned(&mut self) -> bool {
+        if self.state == GuardState::Return { self.set_state(GuardState::Idle) } else { false }
+    }
+
+    fn set_state(&mut self, state: GuardState) -> bool {
+        if state == self.state {
+            return false;
+        }
+        if state == GuardState::Alert {
+            self.suspicion = 1.0;
+        }
+        self.state = state;
+        self.state_time = 0.0;
+        return true;
+    }
+}
+
+// How well a guard can see the player this frame, from 0 to 1. Close, in the middle of the view cone and moving is easiest
+// to spot, far away at the edge of the cone and standing still is hardest. Only called once the player is known to be in view.
+pub fn sight_clarity(distance: f32, visual_range: f32, angle: f32, vision_cone_angle: f32, target_moving: bool) -> f32 {
+    let distance_factor = 1.0 - 0.7 * (distance / visual_range).clamp(0.0, 1.0);
+    let angle_factor = 1.0 - 0.5 * (angle / vision_cone_angle).clamp(0.0, 1.0);
+    let movement_factor = if target_moving { 1.0 } else { 0.5 };
+    distance_factor * angle_factor * movement_factor
+}
+
+impl Default for GuardBrain {
+    fn default() -> GuardBrain {
+        GuardBrain::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the brain for a number of seconds at 60fps with the same view of the player
+    fn run(brain: &mut GuardBrain, clarity: f32, seconds: f32) {
+        let delta = 1.0 / 60.0;
+        for _ in 0..(seconds / delta).round() as i32 {
+            brain.update(clarity, delta);
+        }
+    }
+
+    #[test]
+    fn test_glimpse_makes_guard_suspicious() {
+        let mut brain = GuardBrain::new();
+        assert!(brain.update(0.5, 0.1));
+        assert_eq!(brain.state, GuardState::Suspicious);
+    }
+
+    #[test]
+    fn test_clear_view_alerts_faster_than_faint_view() {
+        let mut clear = GuardBrain::new();
+        run(&mut clear, 1.0, 1.1);
+        assert_eq!(clear.state, GuardState::Alert);
+
+        let mut faint = GuardBrain::new();
+        run(&mut faint, 0.3, 1.1);
+        assert_eq!(faint.state, GuardState::Suspicious);
+        run(&mut faint, 0.3, 2.5);
+        assert_eq!(faint.state, GuardState::Alert);
+    }
+
+    #[test]
+    fn test_suspicion_fades_and_guard_returns() {
+        let mut brain = GuardBrain::new();
+        run(&mut brain, 1.0, 0.5);
+        assert_eq!(brain.state, GuardState::Suspicious);
+
+        run(&mut brain, 0.0, 1.0);
+        assert_eq!(brain.state, GuardState::Suspicious);
+        run(&mut brain, 0.0, 1.5);
+        assert_eq!(brain.state, GuardState::Return);
+
+        assert!(brain.returned());
+        assert_eq!(brain.state, GuardState::Idle);
+    }
+
+    #[test]
+    fn test_lost_player_is_searched_for() {
+        let mut brain = GuardBrain::new();
+        run(&mut brain, 1.0, 1.5);
+        assert_eq!(brain.state, GuardState::Alert);
+
+        run(&mut brain, 0.0, ALERT_LOSE_TIME + 0.1);
+        assert_eq!(brain.state, GuardState::Search);
+
+        // Still wary while searching, so a short look is enough to give chase again
+        run(&mut brain, 0.0, 3.0);
+        run(&mut brain, 1.0, 0.5);
+        assert_eq!(brain.state, GuardState::Alert);
+
+        run(&mut brain, 0.0, ALERT_LOSE_TIME + SEARCH_TIME + 0.1);
+        assert_eq!(brain.state, GuardState::Return);
+    }
+
+    #[test]
+    fn test_meter_needs_to_fill_before_chasing() {
+        let mut brain = GuardBrain::new();
+        run(&mut brain, 0.5, 1.5);
+        assert_eq!(brain.state, GuardState::Suspicious);
+        assert!((brain.suspicion - 0.75).abs() < 0.01);
+
+        // Drains while out of sight, a guard that only caught glimpses never gives chase
+        run(&mut brain, 0.0, 2.0);
+        assert!((brain.suspicion - 0.25).abs() < 0.01);
+        run(&mut brain, 0.5, 1.0);
+        assert_eq!(brain.state, GuardState::Suspicious);
+    }
+
+    #[test]
+    fn test_sight_clarity() {
+        let close_centered_moving = sight_clarity(0.0, 500.0, 0.0, 0.5, true);
+        assert!((close_centered_moving - 1.0).abs() < 0.001);
+
+        let far = sight_clarity(500.0, 500.0, 0.0, 0.5, true);
+        let edge_of_cone = sight_clarity(0.0, 500.0, 0.5, 0.5, true);
+        let still = sight_clarity(0.0, 500.0, 0.0, 0.5, false);
+        assert!(far < close_centered_moving);
+        assert!(edge_of_cone < close_centered_moving);
+        assert!(still < close_centered_moving);
+
+        let hardest = sight_clarity(500.0, 500.0, 0.5, 0.5, false);
+        assert!(hardest > 0.0 && hardest < far.min(edge_of_cone).min(still));
+    }
+
+    #[test]
+    fn test_noise_is_investigated() {
+        let mut brain = GuardBrain::new();
+        assert!(brain.investigate());
+        assert_eq!(brain.state, GuardState::Search);
+
+        run(&mut brain, 0.0, SEARCH_TIME - 1.0);
+        assert!(brain.investigate());
+        run(&mut brain, 0.0, 2.0);
+        assert_eq!(brain.state, GuardState::Search, "A new noise restarts the search");
+
+        let mut chasing = GuardBrain::new();
+        run(&mut chasing, 1.0, 1.5);
+        assert!(!chasing.investigate());
+        assert_eq!(chasing.state, GuardState::Alert);
+    }
+
+    #[test]
+    fn test_returning_guard_can_be_alerted_again() {
+        let mut brain = GuardBrain::new();
+        brain.state = GuardState::Return;
+        brain.update(1.0, 0.1);
+        assert_eq!(brain.state, GuardState::Suspicious);
+        assert!(!brain.returned(), "Only a returning guard can finish returning");
+    }
+
+    #[test]
+    fn test_search_can_end_early() {
+        let mut brain = GuardBrain::new();
+        assert!(!brain.end_search());
+        brain.investigate();
+        assert!(brain.end_search());
+        assert_eq!(brain.state, GuardState::Return);
+    }
+}
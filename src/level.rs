@@ -1,23 +1,13 @@
-use bevy::{
-    asset::{AssetLoader, LoadContext, LoadedAsset}, 
-    prelude::*,
-    reflect::TypeUuid,
-    utils::BoxedFuture,
-};
+use bevy::{asset::LoadState, prelude::*};
 use bevy_rapier2d::prelude::*;
-use geo::{Coordinate, MultiPolygon, Polygon};
+use geo::algorithm::bounding_rect::BoundingRect;
+use geo::{Coordinate, MultiPolygon, Polygon, Rect};
 use geo_visibility::Visibility;
-use pathfinding::prelude::{absdiff, astar};
 
-
-#[derive(Clone, PartialEq)]
-pub enum TileValue {
-    Empty,
-    Wall,
-    Pickup,
-    Player,
-    Enemy,
-}
+use crate::archetype::EnemyArchetypes;
+use crate::gamestate::{CurrentLevel, GameState};
+use crate::level_data::{tile_vector_to_block_set, tile_vector_to_wall_set, LevelTiles, TileValue};
+use crate::level_gen::{generate_level, GenParams};
 
 pub struct LevelPlugin;
 
@@ -25,269 +15,19 @@ impl Plugin for LevelPlugin {
     fn build(&self, app: &mut AppBuilder) {
         app
             .add_system(level_builder_system.system())
+            .add_system_set(SystemSet::on_update(GameState::Playing)
+                .with_system(export_generated_level_system.system())
+                .with_system(level_reload_system.system())
+            )
+            .add_system_set(SystemSet::on_update(GameState::Editor)
+                .with_system(level_reload_system.system())
+            )
             .add_asset::<LevelTiles>()
             .init_asset_loader::<LevelTiles>()
         ;
     }
 }
 
-#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
-pub struct GridPos {
-    pub x: i32, pub y: i32
-}
-
-impl GridPos {
-    fn distance(&self, other: &GridPos) -> u32 {
-        (absdiff(self.x, other.x) + absdiff(self.y, other.y)) as u32
-    }
-}
-
-#[derive(TypeUuid, Default)]
-#[uuid = "47a4a589-01e1-4c15-af08-98b2d0778f28"]
-pub struct LevelTiles {
-    width: usize,
-    height: usize,
-    tile_size: f32,
-    tiles: Vec<TileValue>,
-    pickups_total: i32,
-    _next_level: String, // TODO: Add win condition so this can do something
-}
-
-impl AssetLoader for LevelTiles {
-    fn load<'a>(
-        &'a self,
-        bytes: &'a [u8],
-        load_context: &'a mut LoadContext,
-    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
-        Box::pin(async move {
-            let mut tiles = Vec::<TileValue>::new();
-            let mut width = 0;
-            let mut height = 0;
-            let mut index = 0;
-            let mut next_level: String = "".to_string();
-            let mut read_name = true;
-            let mut pickups_total = 0;
-
-            for byte in bytes {
-                if read_name {
-                    let character = *byte as char;
-                    if character == '\n' {
-                        read_name = false;
-                        println!("Next Level will be {}", next_level);
-                    }
-                    else {
-                        next_level.push( *byte as char);
-                    }
-                    
-                }
-                else{
-                    match *byte as char {
-                        ' ' => { tiles.push(TileValue::Empty); index += 1; },
-                        '#' => { tiles.push(TileValue::Wall); index += 1; },
-                        '$' => { 
-                            tiles.push(TileValue::Pickup); 
-                            index += 1; 
-                            pickups_total += 1;
-                        },
-                        'V' => { tiles.push(TileValue::Player); index += 1; },
-                        'X' => { tiles.push(TileValue::Enemy); index += 1; },
-                        '\n' => {
-                            if width == 0 { width = index; }
-                            height += 1;
-                        },
-                        _ => ()
-                    }
-                }
-            }
-
-            load_context.set_default_asset(LoadedAsset::new(LevelTiles{width, height, tile_size: 50.0, tiles, pickups_total, _next_level: next_level}));
-            Ok(())
-        })
-    }
-
-    fn extensions(&self) -> &[&str] {
-        &["level"]
-    }
-}
-
-impl LevelTiles {
-    pub fn get_path(&self, from: Vec2, to: Vec2) -> Option<Vec<Vec2>> {
-        let goal = self.world_to_grid(to);
-        let start = self.world_to_grid(from);
-        let result = astar(
-            &start, 
-            |pos| self.successors(pos),
-            |pos|  pos.distance(&goal) / 3,
-            |pos| *pos == goal
-        );
-
-        if let Some((grid_points, _path_length)) = result {
-            return Some(grid_points.into_iter().map(|pos| self.grid_to_world(pos)).collect::<Vec<Vec2>>());
-        }
-        else {
-            return None;
-        }
-    }
-
-
-    fn grid_to_world(&self, pos: GridPos) -> Vec2 {
-        Vec2::new(
-            (self.width / 2) as f32 * -self.tile_size + (pos.x as f32 * self.tile_size), 
-            (self.height / 2) as f32 * -self.tile_size + (pos.y as f32 * self.tile_size)
-        )
-    }
-
-    fn world_to_grid(&self, pos: Vec2) -> GridPos {
-        GridPos {
-            x: ((pos.x) / self.tile_size).round() as i32 + (self.width as i32 / 2),
-            y: ((pos.y) / self.tile_size).round() as i32 + (self.height as i32 / 2),
-        }
-    }
-
-    fn get_tile(&self, pos: &GridPos) -> TileValue {
-        if pos.x < 0 || pos.y < 0 || pos.x as usize > self.width || pos.y as usize > self.height {
-             return TileValue::Wall; 
-        }
-
-        return self.tiles[pos.x as usize + (pos.y as usize * self.width)].clone();
-    }
-
-    fn test_successor(&self, pos_test: &GridPos, successor_vec: &mut Vec<(GridPos, u32)>, cost: u32) -> bool{
-        if self.get_tile(pos_test) != TileValue::Wall {
-            successor_vec.push((pos_test.clone(), cost));
-            return true;
-        }
-        return false;
-    }
-
-    fn successors(&self, pos: &GridPos) -> Vec<(GridPos, u32)> {
-        let mut successors = Vec::<(GridPos, u32)>::new();
-        let east = self.test_successor(&GridPos{x: pos.x + 1, y: pos.y}, &mut successors, 2);
-        let west = self.test_successor(&GridPos{x: pos.x - 1, y: pos.y}, &mut successors, 2);
-        let north = self.test_successor(&GridPos{x: pos.x, y: pos.y + 1}, &mut successors, 2);
-        let south = self.test_successor(&GridPos{x: pos.x, y: pos.y - 1}, &mut successors, 2);
-        if east && north { self.test_successor(&GridPos{x: pos.x + 1, y: pos.y + 1}, &mut successors, 3); }
-        if east && south { self.test_successor(&GridPos{x: pos.x + 1, y: pos.y - 1}, &mut successors, 3); }
-        if west && north { self.test_successor(&GridPos{x: pos.x - 1, y: pos.y + 1}, &mut successors, 3); }
-        if west && south { self.test_successor(&GridPos{x: pos.x - 1, y: pos.y - 1}, &mut successors, 3); }
-        return successors;
-    }
-
-    /*fn to_level_objects(&self) -> LevelObjects {
-
-    }*/
-}
-
-fn get_tile_index(x: usize, y: usize, width: usize) -> usize {
-    x+ (y * width)
-}
-
-fn count_wall_continues_x(tiles: &Vec<bool>, width: usize, _height: usize, start: &IVec2) -> usize {
-    let mut x = start.x as usize;
-    let y = start.y as usize;
-    let mut cur_length = 0;
-    let mut index = get_tile_index(x, y, width);
-
-    while x < width && tiles[index] {
-        x += 1;
-        cur_length += 1;
-        index = get_tile_index(x, y, width); // May be same as +1 for now but safety
-    }
-
-    return cur_length;
-}
-
-fn count_wall_continues_y(tiles: &Vec<bool>, width: usize, height: usize, start: &IVec2) -> usize {
-    let x = start.x as usize;
-    let mut y = start.y as usize;
-    let mut cur_length = 0;
-    let mut index = get_tile_index(x, y, width);
-
-    while y < height && tiles[index] {
-        y += 1;
-        cur_length += 1;
-        index = get_tile_index(x, y, width);
-    }
-
-    return cur_length;
-}
-
-fn take_longest_wall(tiles: &mut Vec<bool>, width: usize, height: usize, root: &IVec2) -> Wall {
-    let x_wall_length = count_wall_continues_x(&tiles, width, height, &root);
-    let y_wall_length = count_wall_continues_y(&tiles, width, height, &root);
-
-    if x_wall_length > y_wall_length {
-        let mut x = root.x as usize;
-        while x < root.x as usize + x_wall_length {
-            tiles[get_tile_index(x, root.y as usize, width)] = false;
-            x += 1;
-        }
-        return Wall{top_left: root.clone(), bottom_right: IVec2::new(x as i32 - 1 , root.y)};
-    }
-    else {
-        let mut y = root.y as usize;
-        while y < root.y as usize + y_wall_length {
-            tiles[get_tile_index(root.x as usize, y, width)] = false;
-            y += 1;
-        }
-        return Wall{top_left: root.clone(), bottom_right: IVec2::new(root.x, y as i32 - 1)};
-    }
-}
-
-fn tile_vector_to_wall_set(tiles: &Vec<TileValue>, width: usize, height: usize) -> Vec<Wall> {
-    let mut remaining_wall_tiles = Vec::<bool>::new();
-    let mut walls = Vec::<Wall>::new();
-
-    for tile in tiles {
-        remaining_wall_tiles.push(tile.clone() == TileValue::Wall);
-    }
-
-    for x in 0..width {
-        for y in 0..height {
-            let index = get_tile_index(x, y, width);
-
-            if remaining_wall_tiles[index] {
-                walls.push(take_longest_wall(&mut remaining_wall_tiles, width, height, &IVec2::new(x as i32, y as i32)));
-            }
-        }
-    }
-
-    return walls;
-}
-
-#[derive(Eq, Debug)]
-struct Wall {
-    top_left: IVec2,
-    bottom_right: IVec2,
-}
-
-impl Wall {
-    fn get_center(&self, tile_size: f32) -> Vec2 {
-        // Move out bottom right corner by 1 to include bottom right tile
-        let top = i32::max(self.top_left.y, self.bottom_right.y);
-        let bottom = i32::min(self.top_left.y, self.bottom_right.y);
-        let left = i32::min(self.top_left.x, self.bottom_right.x);
-        let right = i32::max(self.top_left.x, self.bottom_right.x);
-
-        let top_left = Vec2::new(left as f32, top as f32) * tile_size;
-        let bottom_right = Vec2::new(right as f32, bottom as f32) * tile_size;
-        return 0.5 * (top_left + bottom_right);
-    }
-
-    fn get_size(&self, tile_size: f32) -> Vec2 {
-        // Add 1 for inclusive range
-        let tile_height = (self.top_left.y - self.bottom_right.y).abs() + 1;
-        let tile_width = (self.top_left.x - self.bottom_right.x).abs() + 1;
-        return Vec2::new(tile_width as f32 * tile_size, tile_height as f32 * tile_size);
-    }
-}
-
-impl PartialEq for Wall {
-    fn eq(&self, other: &Wall) -> bool {
-        self.top_left == other.top_left && self.bottom_right == other.bottom_right
-    }
-}
-
 pub struct LevelGeo {
     level_blocks: Vec<Polygon<f64>>,
     temp_blocks: Vec<Polygon<f64>>,
@@ -304,26 +44,92 @@ impl LevelGeo {
         return MultiPolygon(all_blocks);
     }
 
+    // Bounds of the temporary blocks overlapping the square that reaches this far around a point
+    pub fn temp_blocks_near(&self, center: Vec2, reach: f32) -> Vec<Rect<f64>> {
+        self.temp_blocks.iter()
+            .filter_map(|block| block.bounding_rect())
+            .filter(|rect| rect_near(rect, center, reach))
+            .collect()
+    }
+
+    // Edges of everything blocking light within reach of a point, for casting rays against
+    pub fn blocker_segments_near(&self, center: Vec2, reach: f32) -> Vec<(Vec2, Vec2)> {
+        self.level_blocks.iter().chain(self.temp_blocks.iter())
+            .filter(|block| block.bounding_rect().map_or(false, |rect| rect_near(&rect, center, reach)))
+            .flat_map(|block| block.exterior().lines())
+            .map(|line| (Vec2::new(line.start.x as f32, line.start.y as f32), Vec2::new(line.end.x as f32, line.end.y as f32)))
+            .collect()
+    }
+
     pub fn reset_temps_for_next_frame(&mut self) {
         self.temp_blocks.clear();
     }
 }
 
+// Whether the rectangle overlaps the square that reaches this far around a point
+fn rect_near(rect: &Rect<f64>, center: Vec2, reach: f32) -> bool {
+    let (low, high) = (center - Vec2::splat(reach), center + Vec2::splat(reach));
+    rect.max().x >= low.x as f64 && rect.min().x <= high.x as f64
+        && rect.max().y >= low.y as f64 && rect.min().y <= high.y as f64
+}
+
+const WALL_COLOR: Color = Color::rgb(0.4, 0.3, 0.6);
+const MIRROR_COLOR: Color = Color::rgb(0.75, 0.85, 0.9);
+
 pub struct LevelState {
     built: bool
 }
 
+// Everything spawned from the level data, gets despawned when the level file changes and the level is rebuilt.
+// Only tag top level entities, children go with their parent and anything attached to the player stays with it.
+pub struct LevelEntity;
+
 pub fn setup_environment(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
-    current_level: Res<crate::gamestate::CurrentLevel>,
+    mut levels: ResMut<Assets<LevelTiles>>,
+    current_level: Res<CurrentLevel>,
 ) {
-    let level_path = "levels/".to_string() + &current_level.name + ".level";
-    println!("Preparing level: {}", level_path);
-    let level_handle: Handle<LevelTiles> = asset_server.load(&level_path as &str);
+    let level_handle: Handle<LevelTiles> = if let Some(editing) = &current_level.editing {
+        println!("Play-testing level: {}", current_level.name);
+        editing.clone()
+    }
+    else if let Some(generated) = &current_level.generated {
+        println!("Generating level from seed {}", generated.seed);
+        let mut level = generate_level(generated.seed, &GenParams::endless(generated.depth));
+        level.set_title(&format!("Generated Level {} (Seed {})", generated.depth + 1, generated.seed));
+        levels.add(level)
+    }
+    else {
+        let level_path = "levels/".to_string() + &current_level.name + ".level";
+        println!("Preparing level: {}", level_path);
+        asset_server.load(&level_path as &str)
+    };
     spawn_level(&mut commands, level_handle);
 }
 
+// Saves the generated level being played so a good seed can be kept as a regular level file
+pub fn export_generated_level_system(
+    keyboard_input: Res<Input<KeyCode>>,
+    current_level: Res<CurrentLevel>,
+    levels: Res<Assets<LevelTiles>>,
+    level_query: Query<&Handle<LevelTiles>>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::F5) { return; }
+
+    if let Some(generated) = &current_level.generated {
+        if let Ok(level_handle) = level_query.single() {
+            if let Some(level) = levels.get(level_handle) {
+                let path = format!("assets/levels/generated_{}.level", generated.seed);
+                match std::fs::write(&path, level.to_level_string()) {
+                    Ok(()) => println!("Saved generated level to {}", path),
+                    Err(err) => println!("Couldn't save generated level to {}: {}", path, err),
+                }
+            }
+        }
+    }
+}
+
 pub fn spawn_level(commands: &mut Commands, level: Handle<LevelTiles>) {
     commands.spawn()
         .insert(level)
@@ -339,13 +145,14 @@ fn create_static_box(commands: &mut Commands,
     materials: &mut ResMut<Assets<ColorMaterial>>,
     rapier_config: &Res<RapierConfiguration>,
     level_geo: &mut Vec<Polygon<f64>>,
-    position: Vec2, size: Vec2
+    position: Vec2, size: Vec2,
+    color: Color,
 ) {
     let collider_size_x = size.x / rapier_config.scale;
     let collider_size_y = size.y / rapier_config.scale;
 
     commands.spawn_bundle(SpriteBundle {
-        material: materials.add(Color::rgb(0.4, 0.3, 0.6).into()),
+        material: materials.add(color.into()),
         sprite: Sprite::new(size),
         ..Default::default()
     })
@@ -357,7 +164,8 @@ fn create_static_box(commands: &mut Commands,
         mass_properties: ColliderMassProps::Density(2.0),
         ..Default::default()
     })
-    .insert(ColliderPositionSync::Discrete);
+    .insert(ColliderPositionSync::Discrete)
+    .insert(LevelEntity);
     
     let min_point = position + (-0.5 * size) * 0.999;
     let max_point = position + (0.5 * size) * 0.999;
@@ -365,6 +173,46 @@ fn create_static_box(commands: &mut Commands,
     level_geo.push(geo::Rect::new(bevy_vec2_to_geo_coord(min_point), bevy_vec2_to_geo_coord(max_point)).into());
 }
 
+// Clears out the old level when its file is saved so level_builder_system builds the new version.
+// The player is kept where they are unless the new layout has put a wall on top of them.
+pub fn level_reload_system(
+    mut commands: Commands,
+    state: Res<State<GameState>>,
+    mut asset_events: EventReader<AssetEvent<LevelTiles>>,
+    levels: Res<Assets<LevelTiles>>,
+    mut score: ResMut<crate::gamestate::Score>,
+    mut level_query: Query<(&mut LevelState, &Handle<LevelTiles>)>,
+    level_entity_query: Query<Entity, (With<LevelEntity>, Without<crate::player::PlayerMovement>, Without<Parent>)>,
+    player_query: Query<(Entity, &Transform), With<crate::player::PlayerMovement>>,
+) {
+    if let Ok((mut level_state, level_data_handle)) = level_query.single_mut() {
+        for event in asset_events.iter() {
+            if let AssetEvent::Modified { handle } = event {
+                if handle != level_data_handle || !level_state.built { continue; }
+
+                if let Some(level_data) = levels.get(level_data_handle) {
+                    println!("Level changed, rebuilding");
+
+                    for entity in level_entity_query.iter() {
+                        commands.entity(entity).despawn_recursive();
+                    }
+
+                    if let Ok((player, transform)) = player_query.single() {
+                        // The editor always shows the player on the spawn tile, which may have just moved
+                        if *state.current() == GameState::Editor || !level_data.is_walkable_at(transform.translation.truncate()) {
+                            commands.entity(player).despawn_recursive();
+                        }
+                    }
+
+                    // All the cards come back so collecting starts over
+                    score.value = 0;
+                    level_state.built = false;
+                }
+            }
+        }
+    }
+}
+
 pub fn level_builder_system(
     mut commands: Commands,
     mut materials: ResMut<Assets<ColorMaterial>>,
@@ -373,19 +221,42 @@ pub fn level_builder_system(
     asset_server: Res<AssetServer>,
     render_data: ResMut<crate::lighting::LightRenderData>,
     mut score: ResMut<crate::gamestate::Score>,
+    mut current_level: ResMut<CurrentLevel>,
     mut meshes: ResMut<Assets<Mesh>>,
-    mut level_query: Query<(&mut LevelState, &Handle<LevelTiles>, &mut LevelGeo)>
+    mut nav_grid: ResMut<crate::nav::NavGrid>,
+    // Grouped to stay under the limit on how many parameters a system can have
+    (mut ambient_light, mut mirrors): (ResMut<crate::lighting::AmbientLight>, ResMut<crate::mirror::Mirrors>),
+    archetype_assets: Res<Assets<EnemyArchetypes>>,
+    archetype_library: Option<Res<crate::ai::ArchetypeLibrary>>,
+    mut level_query: Query<(&mut LevelState, &Handle<LevelTiles>, &mut LevelGeo)>,
+    player_query: Query<&crate::player::PlayerMovement>,
 ) {
     if let Ok((mut level_state, level_data_handle, mut level_geo)) = level_query.single_mut() {
         if level_state.built { return; }
 
+        // Enemies can't be spawned until it's known what each kind is, if the file is broken they're all plain guards
+        let fallback_archetypes = EnemyArchetypes::default();
+        let archetypes = match archetype_library.as_ref().map(|library| (archetype_assets.get(&library.handle), asset_server.get_load_state(&library.handle))) {
+            Some((Some(archetypes), _)) => archetypes,
+            Some((None, LoadState::Failed)) => &fallback_archetypes,
+            _ => return,
+        };
+
         if let Some(level_data) = levels.get(level_data_handle){
             let mut level_polygons = Vec::<Polygon<f64>>::new();
 
             score.max = level_data.pickups_total;
+            current_level.next = level_data.next_level.clone();
+            current_level.title = level_data.title().to_string();
+            current_level.par_time = level_data.par_time();
+            *nav_grid = crate::nav::NavGrid::new(level_data);
+            ambient_light.level = level_data.ambient().unwrap_or(crate::lighting::DEFAULT_AMBIENT);
+            *mirrors = crate::mirror::Mirrors(crate::mirror::level_mirrors(level_data));
 
             let offset = Vec2::new((level_data.width / 2) as f32 * -level_data.tile_size, (level_data.height / 2) as f32 * -level_data.tile_size);
 
+            let patrol_routes = level_data.patrol_routes();
+
             let wall_list = tile_vector_to_wall_set(&level_data.tiles, level_data.width, level_data.height);
 
             for wall in wall_list {
@@ -395,7 +266,21 @@ pub fn level_builder_system(
                     &rapier_config,
                     &mut level_polygons,
                     wall.get_center(level_data.tile_size) + offset,
-                    wall.get_size(level_data.tile_size)
+                    wall.get_size(level_data.tile_size),
+                    WALL_COLOR,
+                );
+            }
+
+            // Solid like walls, the light and sight bouncing off them is worked out from the mirror faces
+            for mirror in tile_vector_to_block_set(&level_data.tiles, level_data.width, level_data.height, TileValue::Mirror) {
+                create_static_box(
+                    &mut commands,
+                    &mut materials,
+                    &rapier_config,
+                    &mut level_polygons,
+                    mirror.get_center(level_data.tile_size) + offset,
+                    mirror.get_size(level_data.tile_size),
+                    MIRROR_COLOR,
                 );
             }
 
@@ -410,16 +295,21 @@ pub fn level_builder_system(
                             &asset_server,
                         );
                     }
-                    else if matches!(level_data.tiles[x + (y * level_data.width)], TileValue::Player) {
+                    else if matches!(level_data.tiles[x + (y * level_data.width)], TileValue::Player) && player_query.iter().next().is_none() {
                         crate::player::spawn_player(
                             tile_pos,
                             &mut commands,
                             &mut materials,
                             &rapier_config,
                             &asset_server,
+                            &mut meshes,
+                            &render_data,
                         );
                     }
                     else if matches!(level_data.tiles[x + (y * level_data.width)], TileValue::Enemy) {
+                        let patrol_route = patrol_routes.get(&(x + (y * level_data.width)))
+                            .map(|route| route.iter().map(|pos| level_data.grid_to_world(pos.clone())).collect())
+                            .unwrap_or_default();
                         crate::ai::spawn_enemy(
                             &mut commands, 
                             &mut materials, 
@@ -427,7 +317,18 @@ pub fn level_builder_system(
                             &asset_server, 
                             &mut meshes, 
                             &render_data, 
-                            tile_pos
+                            tile_pos,
+                            &archetypes.resolve(&level_data.enemy_params(x + (y * level_data.width))),
+                            patrol_route,
+                        );
+                    }
+                    else if matches!(level_data.tiles[x + (y * level_data.width)], TileValue::Lamp) {
+                        crate::lighting::spawn_lamp(
+                            &mut commands,
+                            &mut meshes,
+                            &render_data,
+                            tile_pos,
+                            &level_data.lamp_params(x + (y * level_data.width)),
                         );
                     }
                 }
@@ -445,200 +346,8 @@ pub fn get_visibility_polygon(level_geo: &LevelGeo, from_point: Vec2) -> Polygon
     return point.visibility(&level_geo.get_geo_multipoly());
 }
 
-fn _gen_level_tiles(width: usize, height: usize) -> LevelTiles {
-    let mut tiles = Vec::<TileValue>::new();
-    for y in 0..height {
-        for x in 0..width {
-            tiles.push(
-                if (((x * y) % 3 == 1) && ((x * y / 3) % 4 == 1)) || x * y == 0 || x == width - 1 || y == height - 1 {TileValue::Wall} 
-                else {TileValue::Empty}
-            );
-        }
-    }
-    LevelTiles { width, height, tile_size: 50.0, tiles, _next_level: "".to_string(), pickups_total: 0 }
+// Visibility from a point with only the walls in the way
+pub fn get_static_visibility_polygon(level_geo: &LevelGeo, from_point: Vec2) -> Polygon<f64>{
+    let point = geo::Point::new(from_point.x as f64, from_point.y as f64);
+    return point.visibility(&MultiPolygon(level_geo.level_blocks.clone()));
 }
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_wall_rect_single_tile() {
-        let wall = Wall{top_left: IVec2::new(0, 0), bottom_right: IVec2::new(0, 0)};
-        assert_eq!(wall.get_center(10.0), Vec2::new(0.0, 0.0));
-        assert_eq!(wall.get_size(10.0), Vec2::new(10.0, 10.0));
-    }
-
-    #[test]
-    fn test_wall_rect_long_x() {
-        let wall = Wall{top_left: IVec2::new(0, 0), bottom_right: IVec2::new(9, 0)};
-        assert_eq!(wall.get_center(10.0), Vec2::new(45.0, 0.0));
-        assert_eq!(wall.get_size(10.0), Vec2::new(100.0, 10.0));
-    }
-
-    #[test]
-    fn test_wall_rect_long_y() {
-        let wall = Wall{top_left: IVec2::new(0, 0), bottom_right: IVec2::new(0, 9)};
-        assert_eq!(wall.get_center(10.0), Vec2::new(0.0, 45.0));
-        assert_eq!(wall.get_size(10.0), Vec2::new(10.0, 100.0));
-    }
-
-    #[test]
-    fn test_wall_rect_square() {
-        let wall = Wall{top_left: IVec2::new(0, 0), bottom_right: IVec2::new(9, 9)};
-        assert_eq!(wall.get_center(10.0), Vec2::new(45.0, 45.0));
-        assert_eq!(wall.get_size(10.0), Vec2::new(100.0, 100.0));
-    }
-
-    #[test]
-    fn test_wall_x_length_single() {
-        let test_grid = vec![   true,  false, false, 
-                                false, false, false,
-                                false, false, false,
-                            ];
-        
-        assert_eq!(count_wall_continues_x(&test_grid, 3, 3, &IVec2::new(0, 0)), 1);
-    }
-
-    #[test]
-    fn test_wall_x_length_mid() {
-        let test_grid = vec![   true,  true,  false, 
-                                false, false, false,
-                                false, false, false,
-                            ];
-        
-        assert_eq!(count_wall_continues_x(&test_grid, 3, 3, &IVec2::new(0, 0)), 2);
-    }
-
-    #[test]
-    fn test_wall_x_length_wholeside() {
-        let test_grid = vec![   true,  true,  true, 
-                                false, false, false,
-                                false, false, false,
-                            ];
-        
-        assert_eq!(count_wall_continues_x(&test_grid, 3, 3, &IVec2::new(0, 0)), 3);
-    }
-
-    #[test]
-    fn test_wall_y_length_single() {
-        let test_grid = vec![   true,  false, false, 
-                                false, false, false,
-                                false, false, false,
-                            ];
-        
-        assert_eq!(count_wall_continues_y(&test_grid, 3, 3, &IVec2::new(0, 0)), 1);
-    }
-
-    #[test]
-    fn test_wall_y_length_mid() {
-        let test_grid = vec![   true,  false,  false, 
-                                true,  false,  false,
-                                false, false,  false,
-                            ];
-        
-        assert_eq!(count_wall_continues_y(&test_grid, 3, 3, &IVec2::new(0, 0)), 2);
-    }
-
-    #[test]
-    fn test_wall_y_length_wholeside() {
-        let test_grid = vec![   true,  false, false, 
-                                true,  false, false,
-                                true,  false, false,
-                            ];
-        
-        assert_eq!(count_wall_continues_y(&test_grid, 3, 3, &IVec2::new(0, 0)), 3);
-    }
-
-    #[test]
-    fn test_takes_longest_wall_x() {
-        let mut test_grid = vec![   true,  true,  true, 
-                                true,  false, false,
-                                false, false, false,
-                            ];
-        let expected_grid = vec![   false, false, false, 
-                                    true,  false, false,
-                                    false, false, false,
-                            ];
-        let expected_wall = Wall{top_left: IVec2::new(0, 0), bottom_right: IVec2::new(2, 0)};
-        
-        let result_wall = take_longest_wall(&mut test_grid, 3, 3, &IVec2::new(0, 0));
-        assert_eq!(result_wall.top_left, expected_wall.top_left, "Return correct wall top left bound");
-        assert_eq!(result_wall.bottom_right, expected_wall.bottom_right, "Return correct wall bottom right bound");
-        assert_eq!(test_grid, expected_grid, "Properly mutate grid");
-    }
-
-    #[test]
-    fn test_takes_longest_wall_y() {
-        let mut test_grid = vec![   true,  true, false, 
-                                    true,  false, false,
-                                    true,  false, false,
-                            ];
-        let expected_grid = vec![   false, true,  false, 
-                                    false, false, false,
-                                    false, false, false,
-                            ];
-        let expected_wall = Wall{top_left: IVec2::new(0, 0), bottom_right: IVec2::new(0, 2)};
-
-        let result_wall = take_longest_wall(&mut test_grid, 3, 3, &IVec2::new(0, 0));
-        assert_eq!(result_wall.top_left, expected_wall.top_left, "Return correct wall top left bound");
-        assert_eq!(result_wall.bottom_right, expected_wall.bottom_right, "Return correct wall bottom right bound");
-        assert_eq!(test_grid, expected_grid, "Properly mutate grid");
-    }
-
-    #[test]
-    fn test_makes_walls_steps() {
-        let test_grid = vec![   TileValue::Wall,  TileValue::Empty, TileValue::Empty, 
-                                    TileValue::Wall,  TileValue::Wall, TileValue::Empty,
-                                    TileValue::Wall,  TileValue::Wall, TileValue::Wall,
-                            ];
-        let expected_walls = vec! [
-            Wall{top_left: IVec2::new(0, 0), bottom_right: IVec2::new(0, 2)},
-            Wall{top_left: IVec2::new(1, 1), bottom_right: IVec2::new(1, 2)},   
-            Wall{top_left: IVec2::new(2, 2), bottom_right: IVec2::new(2, 2)},   
-        ];
-
-        let result_walls = tile_vector_to_wall_set(&test_grid, 3, 3);
-        
-        assert_eq!(result_walls.len(), 3, "3 walls expected");
-
-        for i in 0..3 {
-            assert_eq!(result_walls[i], expected_walls[i], "Wall {} matches", i);
-        }
-
-        assert_eq!(result_walls[0].get_center(10.0), Vec2::new(0.0, 10.0));
-        assert_eq!(result_walls[1].get_center(10.0), Vec2::new(10.0, 15.0));
-        assert_eq!(result_walls[2].get_center(10.0), Vec2::new(20.0, 20.0));
-    }
-
-    #[test]
-    fn test_makes_walls_empty() {
-        let test_grid = vec![   TileValue::Empty,  TileValue::Empty, TileValue::Empty, 
-                                TileValue::Empty,  TileValue::Empty, TileValue::Empty,
-                                TileValue::Empty,  TileValue::Empty, TileValue::Empty,
-                            ];
-        let result_walls = tile_vector_to_wall_set(&test_grid, 3, 3);
-        
-        assert_eq!(result_walls.len(), 0, "Empty grid should produce no walls");
-    }
-
-    #[test]
-    fn test_makes_walls_x() {
-        let test_grid = vec![   TileValue::Empty,  TileValue::Wall, TileValue::Empty, 
-                                TileValue::Wall,   TileValue::Wall, TileValue::Wall,
-                                TileValue::Empty,  TileValue::Wall, TileValue::Empty,
-                            ];
-        let expected_walls = vec! [
-            Wall{top_left: IVec2::new(0, 1), bottom_right: IVec2::new(2, 1)},
-            Wall{top_left: IVec2::new(1, 0), bottom_right: IVec2::new(1, 0)},   
-            Wall{top_left: IVec2::new(1, 2), bottom_right: IVec2::new(1, 2)},   
-        ];
-        let result_walls = tile_vector_to_wall_set(&test_grid, 3, 3);
-        
-        assert_eq!(result_walls.len(), 3, "3 walls expected");
-
-        for i in 0..3 {
-            assert_eq!(result_walls[i], expected_walls[i], "Wall {} matches", i);
-        }
-    }
-}
\ No newline at end of file
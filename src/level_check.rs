@@ -0,0 +1,98 @@
+// Headless linter for level files, doesn't open a window or start the game.
+// Usage: cargo run --bin level-check [level files or folders...]
+// Checks everything in assets/levels when given no paths, exits with an error if any level has errors.
+
+use std::path::{Path, PathBuf};
+
+use smoke_and_mirrors::level_data::{check_level, tile_vector_to_wall_set, GridPos, TileValue};
+
+fn main() {
+    let mut targets = std::env::args().skip(1).collect::<Vec<String>>();
+    if targets.is_empty() {
+        targets.push("assets/levels".to_string());
+    }
+
+    let mut files = Vec::<PathBuf>::new();
+    for target in &targets {
+        if let Err(err) = collect_level_files(Path::new(target), &mut files) {
+            eprintln!("Couldn't read {}: {}", target, err);
+            std::process::exit(2);
+        }
+    }
+    files.sort();
+
+    let failed = files.iter().filter(|file| !check_file(file)).count();
+
+    println!("Checked {} level(s), {} with errors", files.len(), failed);
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+fn collect_level_files(path: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            let entry_path = entry?.path();
+            if entry_path.is_dir() || entry_path.extension().map_or(false, |extension| extension == "level") {
+                collect_level_files(&entry_path, files)?;
+            }
+        }
+    }
+    else {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+// Prints a summary of the level and any problems with it, returns false if the level has errors
+fn check_file(file: &Path) -> bool {
+    let name = file.display().to_string();
+    println!("{}", name);
+
+    let text = match std::fs::read_to_string(file) {
+        Ok(text) => text,
+        Err(err) => {
+            println!("{}: error: {}\n", name, err);
+            return false;
+        }
+    };
+
+    let check = check_level(&text);
+    if let Some(level) = &check.level {
+        if !check.rectangular {
+            println!("{}\n", check.report(&name));
+            return false;
+        }
+
+        let count = |value: TileValue| level.tiles.iter().filter(|tile| **tile == value).count();
+        let walls = tile_vector_to_wall_set(&level.tiles, level.width, level.height);
+        let unreachable = level.unreachable_pickups();
+
+        println!("  size:            {}x{}", level.width, level.height);
+        println!("  wall rectangles: {}", walls.len());
+        println!("  pickups:         {}", count(TileValue::Pickup));
+        println!("  player spawn:    {}", if level.player_spawn().is_some() { "yes" } else { "no" });
+        println!("  unreachable:     {}", format_positions(&unreachable));
+        println!("  enemies:         {}", count(TileValue::Enemy));
+        println!("  patrol routes:   {}", level.patrol_routes().len());
+        println!("  lamps:           {}", count(TileValue::Lamp));
+        println!("  mirrors:         {}", count(TileValue::Mirror));
+    }
+
+    if !check.diagnostics.is_empty() {
+        println!("{}", check.report(&name));
+    }
+    println!();
+
+    !check.has_errors()
+}
+
+fn format_positions(positions: &[GridPos]) -> String {
+    if positions.is_empty() {
+        return "none".to_string();
+    }
+    positions.iter()
+        .map(|pos| format!("({}, {})", pos.x, pos.y))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
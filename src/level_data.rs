@@ -0,0 +1,1519 @@
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset}, 
+    prelude::*,
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use pathfinding::prelude::absdiff;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+pub const DEFAULT_TILE_SIZE: f32 = 50.0;
+pub const LEVEL_FORMAT_VERSION: u32 = 2;
+const HEADER_END: &str = "---";
+// Move costs, diagonals are roughly sqrt(2) times a straight step
+pub const STRAIGHT_COST: u32 = 2;
+pub const DIAGONAL_COST: u32 = 3;
+
+#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
+pub enum TileValue {
+    Empty,
+    Wall,
+    Pickup,
+    Player,
+    Enemy,
+    // Floor tile with a lamp standing on it
+    Lamp,
+    // Wall with a mirror on every side facing into the open
+    Mirror,
+    // Patrol stop for the nearest guard, guards visit their stops in number order
+    Waypoint(u8),
+}
+
+impl TileValue {
+    fn from_default_char(character: char) -> Option<TileValue> {
+        match character {
+            ' ' => Some(TileValue::Empty),
+            '#' => Some(TileValue::Wall),
+            '$' => Some(TileValue::Pickup),
+            'V' => Some(TileValue::Player),
+            'X' => Some(TileValue::Enemy),
+            '*' => Some(TileValue::Lamp),
+            '%' => Some(TileValue::Mirror),
+            '1'..='9' => character.to_digit(10).map(|digit| TileValue::Waypoint(digit as u8)),
+            _ => None
+        }
+    }
+
+    fn default_char(&self) -> char {
+        match self {
+            TileValue::Empty => ' ',
+            TileValue::Wall => '#',
+            TileValue::Pickup => '$',
+            TileValue::Player => 'V',
+            TileValue::Enemy => 'X',
+            TileValue::Lamp => '*',
+            TileValue::Mirror => '%',
+            TileValue::Waypoint(number) => std::char::from_digit(*number as u32, 10).unwrap_or('?'),
+        }
+    }
+
+    // Nothing can walk or see through it
+    pub fn is_solid(&self) -> bool {
+        matches!(self, TileValue::Wall | TileValue::Mirror)
+    }
+}
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct GridPos {
+    pub x: i32, pub y: i32
+}
+
+impl GridPos {
+    pub fn distance(&self, other: &GridPos) -> u32 {
+        (absdiff(self.x, other.x) + absdiff(self.y, other.y)) as u32
+    }
+}
+
+// Tuning for enemies spawned from a level, anything left as None uses the spawner's default
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnemyParams {
+    // Name of an entry in assets/enemies.archetypes, the rest of these override what it sets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archetype: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visual_range: Option<f32>,
+    // In degrees, to keep level files readable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vision_cone_angle: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub move_speed: Option<f32>,
+    // Seconds spent looking around at each patrol waypoint
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub patrol_pause: Option<f32>,
+    // How far away other guards hear this one call out a sighting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub radio_range: Option<f32>,
+}
+
+impl EnemyParams {
+    fn is_unset(&self) -> bool {
+        *self == EnemyParams::default()
+    }
+
+    // Values set on self win, anything unset falls back to the other set of params
+    fn or(&self, fallback: &EnemyParams) -> EnemyParams {
+        EnemyParams {
+            archetype: self.archetype.clone().or_else(|| fallback.archetype.clone()),
+            visual_range: self.visual_range.or(fallback.visual_range),
+            vision_cone_angle: self.vision_cone_angle.or(fallback.vision_cone_angle),
+            move_speed: self.move_speed.or(fallback.move_speed),
+            patrol_pause: self.patrol_pause.or(fallback.patrol_pause),
+            radio_range: self.radio_range.or(fallback.radio_range),
+        }
+    }
+}
+
+// Tuning for lamps placed from a level, anything left as None uses the lamp's default
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LampParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<[f32; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reach: Option<f32>,
+    // How far the brightness dips when the lamp flickers, from 0 for steady to 1 for going fully out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flicker: Option<f32>,
+    // Seconds from one brightest point of a slow pulse to the next
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pulse: Option<f32>,
+}
+
+impl LampParams {
+    fn is_unset(&self) -> bool {
+        *self == LampParams::default()
+    }
+
+    // Values set on self win, anything unset falls back to the other set of params
+    fn or(&self, fallback: &LampParams) -> LampParams {
+        LampParams {
+            color: self.color.or(fallback.color),
+            reach: self.reach.or(fallback.reach),
+            flicker: self.flicker.or(fallback.flicker),
+            pulse: self.pulse.or(fallback.pulse),
+        }
+    }
+}
+
+// A custom character in the level grid and what it places
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LegendEntry {
+    pub tile: TileValue,
+    #[serde(default, skip_serializing_if = "EnemyParams::is_unset")]
+    pub enemy: EnemyParams,
+    #[serde(default, skip_serializing_if = "LampParams::is_unset")]
+    pub lamp: LampParams,
+}
+
+// RON block at the top of a structured level file, terminated by a "---" line
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct LevelHeader {
+    version: u32,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    next_level: String,
+    #[serde(default = "default_tile_size")]
+    tile_size: f32,
+    #[serde(default)]
+    par_time: Option<f32>,
+    // How bright places no light reaches are, from 0 for pitch black to 1 for fully lit
+    #[serde(default)]
+    ambient: Option<f32>,
+    #[serde(default)]
+    enemy: EnemyParams,
+    #[serde(default, skip_serializing_if = "LampParams::is_unset")]
+    lamp: LampParams,
+    #[serde(default)]
+    legend: BTreeMap<char, LegendEntry>,
+}
+
+fn default_tile_size() -> f32 {
+    DEFAULT_TILE_SIZE
+}
+
+#[derive(TypeUuid, Default)]
+#[uuid = "47a4a589-01e1-4c15-af08-98b2d0778f28"]
+pub struct LevelTiles {
+    pub width: usize,
+    pub height: usize,
+    pub tile_size: f32,
+    pub tiles: Vec<TileValue>,
+    pub pickups_total: i32,
+    pub next_level: String,
+    title: String,
+    par_time: Option<f32>,
+    ambient: Option<f32>,
+    enemy_defaults: EnemyParams,
+    lamp_defaults: LampParams,
+    legend: BTreeMap<char, LegendEntry>,
+    // Tiles that were placed by a legend character rather than a default one
+    tile_chars: HashMap<usize, char>,
+}
+
+impl AssetLoader for LevelTiles {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let file = load_context.path().display().to_string();
+            let check = check_level(std::str::from_utf8(bytes)?);
+            for warning in check.diagnostics.iter().filter(|diagnostic| diagnostic.severity == Severity::Warning) {
+                println!("{}:{}", file, warning);
+            }
+
+            let level = check.into_level(&file)?;
+            println!("Next Level will be {}", level.next_level);
+
+            load_context.set_default_asset(LoadedAsset::new(level));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["level"]
+    }
+}
+
+// Level files come in two flavours:
+//
+// Legacy: the first line is the name of the next level and everything after it is the tile grid.
+// Characters that aren't part of the default set are skipped.
+//
+// Structured: a RON header, a line containing only "---", then the tile grid.
+//     (
+//         version: 2,
+//         title: "Warehouse",
+//         next_level: "game",
+//         tile_size: 40.0,
+//         par_time: Some(90.0),
+//         ambient: Some(0.1),
+//         enemy: (visual_range: Some(600.0)),
+//         lamp: (color: Some((1.0, 0.8, 0.5)), reach: Some(300.0)),
+//         legend: {
+//             'H': (tile: Enemy, enemy: (move_speed: Some(250.0), vision_cone_angle: Some(15.0))),
+//             'C': (tile: Enemy, enemy: (archetype: Some("camera"))),
+//             'F': (tile: Lamp, lamp: (flicker: Some(0.6))),
+//         },
+//     )
+//     ---
+//     #####
+//     #V H#
+//     #####
+// Every field except the version is optional. Legend characters are used alongside the default
+// ones and may replace them, any other character in the grid is an error.
+impl LevelTiles {
+    // Reads the file without validating the layout, only fails if the file can't be read at all
+    pub fn parse(text: &str) -> Result<LevelTiles, anyhow::Error> {
+        let mut diagnostics = Vec::<LevelDiagnostic>::new();
+        let level = LevelTiles::read(text, &mut diagnostics);
+
+        if let Some(error) = diagnostics.iter().find(|diagnostic| diagnostic.severity == Severity::Error) {
+            anyhow::bail!("{}", error);
+        }
+        Ok(level.expect("Level should be read when there are no errors").0)
+    }
+
+    fn read(text: &str, diagnostics: &mut Vec<LevelDiagnostic>) -> Option<(LevelTiles, GridSource)> {
+        let mut level = LevelTiles { tile_size: DEFAULT_TILE_SIZE, ..Default::default() };
+
+        if text.trim_start().starts_with('(') {
+            let (header_text, grid_text) = match split_header(text) {
+                Some(split) => split,
+                None => {
+                    diagnostics.push(LevelDiagnostic::error(1, 1, format!("Level header is missing its closing '{}' line", HEADER_END)));
+                    return None;
+                }
+            };
+            let header: LevelHeader = match ron::de::from_str(header_text) {
+                Ok(header) => header,
+                Err(err) => {
+                    diagnostics.push(LevelDiagnostic::error(
+                        err.position.line.max(1),
+                        err.position.col.max(1),
+                        format!("Invalid level header: {}", err.code)
+                    ));
+                    return None;
+                }
+            };
+            if header.version != LEVEL_FORMAT_VERSION {
+                diagnostics.push(LevelDiagnostic::error(1, 1, 
+                    format!("Unsupported level format version {}, expected {}", header.version, LEVEL_FORMAT_VERSION)));
+                return None;
+            }
+
+            level.next_level = header.next_level;
+            level.title = header.title;
+            level.tile_size = header.tile_size;
+            level.par_time = header.par_time;
+            level.ambient = header.ambient;
+            level.enemy_defaults = header.enemy;
+            level.lamp_defaults = header.lamp;
+            level.legend = header.legend;
+
+            // Grid starts on the line after the "---"
+            let first_line = header_text.matches('\n').count() + 2;
+            let source = level.read_grid(grid_text, first_line, true, diagnostics);
+            Some((level, source))
+        }
+        else {
+            let (next_level, grid_text) = text.split_once('\n').unwrap_or((text, ""));
+            level.next_level = next_level.trim_end_matches('\r').to_string();
+            let source = level.read_grid(grid_text, 2, false, diagnostics);
+            Some((level, source))
+        }
+    }
+
+    fn read_grid(&mut self, grid: &str, first_line: usize, strict: bool, diagnostics: &mut Vec<LevelDiagnostic>) -> GridSource {
+        let mut source = GridSource { first_line, ..Default::default() };
+        let mut index = 0;
+        let mut line = first_line;
+        let mut column = 1;
+        let mut row_start = 0;
+
+        for character in grid.chars() {
+            match character {
+                '\n' => {
+                    if self.width == 0 { self.width = index; }
+                    self.height += 1;
+
+                    source.rows.push(GridRow { line, first_tile: row_start, tile_count: index - row_start });
+                    row_start = index;
+                    line += 1;
+                    column = 1;
+                    continue;
+                },
+                '\r' => (),
+                _ => {
+                    let tile = if let Some(entry) = self.legend.get(&character) {
+                        self.tile_chars.insert(self.tiles.len(), character);
+                        Some(entry.tile.clone())
+                    }
+                    else {
+                        TileValue::from_default_char(character)
+                    };
+
+                    match tile {
+                        Some(tile) => {
+                            if tile == TileValue::Pickup { self.pickups_total += 1; }
+                            self.tiles.push(tile);
+                            source.tile_positions.push((line, column));
+                            index += 1;
+                        },
+                        None if strict => {
+                            diagnostics.push(LevelDiagnostic::error(line, column, 
+                                format!("Unknown tile character '{}'", character)));
+                            // Keep the rest of the row lined up so it doesn't also get reported as ragged
+                            self.tiles.push(TileValue::Empty);
+                            source.tile_positions.push((line, column));
+                            index += 1;
+                        },
+                        None => diagnostics.push(LevelDiagnostic::warning(line, column, 
+                            format!("Unknown tile character '{}' is skipped", character))),
+                    }
+                }
+            }
+            column += 1;
+        }
+
+        if index > row_start {
+            source.unterminated_row = Some(line);
+        }
+
+        source
+    }
+
+    // Builds a level straight from a grid of tiles, the first row is the one at the top of a level file
+    pub fn new(width: usize, height: usize, tiles: Vec<TileValue>) -> LevelTiles {
+        let pickups_total = tiles.iter().filter(|tile| **tile == TileValue::Pickup).count() as i32;
+        LevelTiles { width, height, tile_size: DEFAULT_TILE_SIZE, tiles, pickups_total, ..Default::default() }
+    }
+
+    // Writes the level in the format the loader reads, only using the structured header if
+    // something other than the next level needs saving
+    pub fn to_level_string(&self) -> String {
+        let needs_header = !self.title.is_empty()
+            || self.tile_size != DEFAULT_TILE_SIZE
+            || self.par_time.is_some()
+            || self.ambient.is_some()
+            || !self.enemy_defaults.is_unset()
+            || !self.lamp_defaults.is_unset()
+            || !self.legend.is_empty();
+
+        let mut text = if needs_header {
+            let header = LevelHeader {
+                version: LEVEL_FORMAT_VERSION,
+                title: self.title.clone(),
+                next_level: self.next_level.clone(),
+                tile_size: self.tile_size,
+                par_time: self.par_time,
+                ambient: self.ambient,
+                enemy: self.enemy_defaults.clone(),
+                lamp: self.lamp_defaults.clone(),
+                legend: self.legend.clone(),
+            };
+            let config = ron::ser::PrettyConfig::new().with_decimal_floats(true);
+            ron::ser::to_string_pretty(&header, config).expect("Level header should always serialize") 
+                + "\n" + HEADER_END + "\n"
+        }
+        else {
+            self.next_level.clone() + "\n"
+        };
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let index = get_tile_index(x, y, self.width);
+                text.push(self.tile_chars.get(&index).copied().unwrap_or_else(|| self.tiles[index].default_char()));
+            }
+            text.push('\n');
+        }
+
+        return text;
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn par_time(&self) -> Option<f32> {
+        self.par_time
+    }
+
+    pub fn ambient(&self) -> Option<f32> {
+        self.ambient
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.title = title.to_string();
+    }
+
+    // Returns false if nothing changed. There's only ever one player spawn so placing a new one moves it.
+    pub fn set_tile(&mut self, pos: &GridPos, value: TileValue) -> bool {
+        if !self.in_bounds(pos) {
+            return false;
+        }
+
+        let index = get_tile_index(pos.x as usize, pos.y as usize, self.width);
+        if self.tiles[index] == value {
+            return false;
+        }
+
+        if value == TileValue::Player {
+            for tile in self.tiles.iter_mut().filter(|tile| **tile == TileValue::Player) {
+                *tile = TileValue::Empty;
+            }
+        }
+
+        // Any legend character painted over no longer applies
+        self.tile_chars.remove(&index);
+        self.tiles[index] = value;
+        self.pickups_total = self.tiles.iter().filter(|tile| **tile == TileValue::Pickup).count() as i32;
+        return true;
+    }
+
+    // Level wide enemy settings with any override from the legend character at this tile applied
+    pub fn enemy_params(&self, index: usize) -> EnemyParams {
+        match self.tile_chars.get(&index).and_then(|character| self.legend.get(character)) {
+            Some(entry) => entry.enemy.or(&self.enemy_defaults),
+            None => self.enemy_defaults.clone(),
+        }
+    }
+
+    // Level wide lamp settings with any override from the legend character at this tile applied
+    pub fn lamp_params(&self, index: usize) -> LampParams {
+        match self.tile_chars.get(&index).and_then(|character| self.legend.get(character)) {
+            Some(entry) => entry.lamp.or(&self.lamp_defaults),
+            None => self.lamp_defaults.clone(),
+        }
+    }
+
+    fn index_to_grid(&self, index: usize) -> GridPos {
+        GridPos { x: (index % self.width) as i32, y: (index / self.width) as i32 }
+    }
+
+    pub fn player_spawn(&self) -> Option<GridPos> {
+        self.tiles.iter()
+            .take(self.width * self.height)
+            .position(|tile| *tile == TileValue::Player)
+            .map(|index| self.index_to_grid(index))
+    }
+
+    // Every tile that can be walked to from the given start using the same moves as pathfinding
+    pub fn reachable_from(&self, start: &GridPos) -> HashSet<GridPos> {
+        let mut reached = HashSet::<GridPos>::new();
+        let mut frontier = vec![start.clone()];
+        reached.insert(start.clone());
+
+        while let Some(pos) = frontier.pop() {
+            for (next, _cost) in self.successors(&pos) {
+                if reached.insert(next.clone()) {
+                    frontier.push(next);
+                }
+            }
+        }
+
+        return reached;
+    }
+
+    pub fn unreachable_pickups(&self) -> Vec<GridPos> {
+        let reachable = match self.player_spawn() {
+            Some(spawn) => self.reachable_from(&spawn),
+            None => HashSet::new(),
+        };
+
+        (0..self.width * self.height)
+            .filter(|index| self.tiles[*index] == TileValue::Pickup)
+            .map(|index| self.index_to_grid(index))
+            .filter(|pos| !reachable.contains(pos))
+            .collect()
+    }
+
+    // Walkable tiles that can be reached from the start in at most max_steps moves, with how many moves each takes
+    pub fn cells_within(&self, start: &GridPos, max_steps: u32) -> Vec<(GridPos, u32)> {
+        if self.get_tile(start).is_solid() {
+            return vec![];
+        }
+
+        let mut steps = HashMap::<GridPos, u32>::new();
+        let mut cells = vec![(start.clone(), 0)];
+        let mut frontier = VecDeque::<(GridPos, u32)>::new();
+        steps.insert(start.clone(), 0);
+        frontier.push_back((start.clone(), 0));
+
+        while let Some((pos, step)) = frontier.pop_front() {
+            if step >= max_steps { continue; }
+            for (next, _cost) in self.successors(&pos) {
+                if !steps.contains_key(&next) {
+                    steps.insert(next.clone(), step + 1);
+                    cells.push((next.clone(), step + 1));
+                    frontier.push_back((next, step + 1));
+                }
+            }
+        }
+
+        return cells;
+    }
+
+    // Patrol route for each guard by the tile index of its spawn, waypoints in the order they're visited
+    pub fn patrol_routes(&self) -> HashMap<usize, Vec<GridPos>> {
+        let mut routes = HashMap::<usize, Vec<(u8, usize)>>::new();
+        for (waypoint, owner) in self.waypoint_owners() {
+            if let (Some(enemy), TileValue::Waypoint(number)) = (owner, &self.tiles[waypoint]) {
+                routes.entry(enemy).or_default().push((*number, waypoint));
+            }
+        }
+
+        routes.into_iter()
+            .map(|(enemy, mut waypoints)| {
+                waypoints.sort();
+                (enemy, waypoints.into_iter().map(|(_number, index)| self.index_to_grid(index)).collect())
+            })
+            .collect()
+    }
+
+    pub fn unassigned_waypoints(&self) -> Vec<GridPos> {
+        self.waypoint_owners().into_iter()
+            .filter(|(_waypoint, owner)| owner.is_none())
+            .map(|(waypoint, _owner)| self.index_to_grid(waypoint))
+            .collect()
+    }
+
+    // Each waypoint belongs to the guard with the shortest walk to it, found by searching out from every guard at once.
+    // Waypoints no guard can walk to have no owner.
+    fn waypoint_owners(&self) -> Vec<(usize, Option<usize>)> {
+        let tile_count = self.width * self.height;
+        let mut owners = vec![None; tile_count];
+        let mut frontier = VecDeque::<usize>::new();
+
+        for index in (0..tile_count).filter(|index| self.tiles[*index] == TileValue::Enemy) {
+            owners[index] = Some(index);
+            frontier.push_back(index);
+        }
+
+        while let Some(index) = frontier.pop_front() {
+            for (next, _cost) in self.successors(&self.index_to_grid(index)) {
+                let next_index = get_tile_index(next.x as usize, next.y as usize, self.width);
+                if owners[next_index].is_none() {
+                    owners[next_index] = owners[index];
+                    frontier.push_back(next_index);
+                }
+            }
+        }
+
+        (0..tile_count)
+            .filter(|index| matches!(self.tiles[*index], TileValue::Waypoint(_)))
+            .map(|index| (index, owners[index]))
+            .collect()
+    }
+}
+
+fn split_header(text: &str) -> Option<(&str, &str)> {
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        if line.trim_end() == HEADER_END {
+            return Some((&text[..offset], &text[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+// A problem found in a level file, lines and columns start from 1
+#[derive(Clone, Debug, PartialEq)]
+pub struct LevelDiagnostic {
+    pub severity: Severity,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl LevelDiagnostic {
+    fn error(line: usize, column: usize, message: String) -> LevelDiagnostic {
+        LevelDiagnostic { severity: Severity::Error, line, column, message }
+    }
+
+    fn warning(line: usize, column: usize, message: String) -> LevelDiagnostic {
+        LevelDiagnostic { severity: Severity::Warning, line, column, message }
+    }
+}
+
+impl std::fmt::Display for LevelDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}:{}: {}: {}", self.line, self.column, severity, self.message)
+    }
+}
+
+// Where the tiles of a level came from in its file, used to point diagnostics at the right place
+#[derive(Default)]
+struct GridSource {
+    first_line: usize,
+    tile_positions: Vec<(usize, usize)>,
+    rows: Vec<GridRow>,
+    // Line of a final row that has tiles but no line ending
+    unterminated_row: Option<usize>,
+}
+
+struct GridRow {
+    line: usize,
+    first_tile: usize,
+    tile_count: usize,
+}
+
+pub struct LevelCheck {
+    // None if the file couldn't be read far enough to produce a grid
+    pub level: Option<LevelTiles>,
+    pub diagnostics: Vec<LevelDiagnostic>,
+    // Every row has the same width, so tile indices line up with grid positions
+    pub rectangular: bool,
+}
+
+impl LevelCheck {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+
+    // One diagnostic per line, each prefixed with the file name
+    pub fn report(&self, file: &str) -> String {
+        self.diagnostics.iter()
+            .map(|diagnostic| format!("{}:{}", file, diagnostic))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    pub fn into_level(self, file: &str) -> Result<LevelTiles, anyhow::Error> {
+        if self.has_errors() {
+            anyhow::bail!("Level {} is invalid:\n{}", file, self.report(file));
+        }
+        Ok(self.level.expect("Level should be read when there are no errors"))
+    }
+}
+
+// Reads a level file and checks that it is playable: rectangular, exactly one player spawn and
+// every pickup reachable from it
+pub fn check_level(text: &str) -> LevelCheck {
+    let mut diagnostics = Vec::<LevelDiagnostic>::new();
+    let mut rectangular = false;
+    let level = match LevelTiles::read(text, &mut diagnostics) {
+        Some((level, source)) => {
+            rectangular = validate_layout(&level, &source, &mut diagnostics);
+            Some(level)
+        },
+        None => None,
+    };
+
+    LevelCheck { level, diagnostics, rectangular }
+}
+
+// Returns false if the grid isn't rectangular, in which case only the rows have been checked
+fn validate_layout(level: &LevelTiles, source: &GridSource, diagnostics: &mut Vec<LevelDiagnostic>) -> bool {
+    if let Some(line) = source.unterminated_row {
+        diagnostics.push(LevelDiagnostic::warning(line, 1, 
+            "Last row has no line ending so it is not part of the level".to_string()));
+    }
+
+    if level.width == 0 || level.height == 0 {
+        diagnostics.push(LevelDiagnostic::error(source.first_line, 1, "Level has no tiles".to_string()));
+        return false;
+    }
+
+    let mut ragged = false;
+    for row in &source.rows {
+        if row.tile_count != level.width {
+            ragged = true;
+            let column = if row.tile_count > level.width {
+                source.tile_positions[row.first_tile + level.width].1
+            }
+            else if row.tile_count > 0 {
+                source.tile_positions[row.first_tile + row.tile_count - 1].1 + 1
+            }
+            else {
+                1
+            };
+            diagnostics.push(LevelDiagnostic::error(row.line, column, 
+                format!("Row is {} tiles wide but the first row is {}", row.tile_count, level.width)));
+        }
+    }
+    // Tile indices don't line up with grid positions in a ragged level, so nothing else can be checked
+    if ragged { return false; }
+
+    let spawns = (0..level.width * level.height)
+        .filter(|index| level.tiles[*index] == TileValue::Player)
+        .collect::<Vec<usize>>();
+    if spawns.is_empty() {
+        diagnostics.push(LevelDiagnostic::error(source.first_line, 1, "Level has no player spawn".to_string()));
+        return true;
+    }
+    for extra_spawn in spawns.iter().skip(1) {
+        let (line, column) = source.tile_positions[*extra_spawn];
+        diagnostics.push(LevelDiagnostic::error(line, column, "Level has more than one player spawn".to_string()));
+    }
+
+    for pickup in level.unreachable_pickups() {
+        let (line, column) = source.tile_positions[get_tile_index(pickup.x as usize, pickup.y as usize, level.width)];
+        diagnostics.push(LevelDiagnostic::error(line, column, "Pickup can't be reached from the player spawn".to_string()));
+    }
+
+    for waypoint in level.unassigned_waypoints() {
+        let (line, column) = source.tile_positions[get_tile_index(waypoint.x as usize, waypoint.y as usize, level.width)];
+        diagnostics.push(LevelDiagnostic::warning(line, column, "Patrol waypoint can't be reached by any guard".to_string()));
+    }
+
+    true
+}
+
+// How a level's grid sits in the world, the middle tile is centered on the origin
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GridSpace {
+    // Grid position of the tile at the origin
+    origin: IVec2,
+    tile_size: f32,
+}
+
+impl GridSpace {
+    pub fn new(width: usize, height: usize, tile_size: f32) -> GridSpace {
+        GridSpace { origin: IVec2::new((width / 2) as i32, (height / 2) as i32), tile_size }
+    }
+
+    pub fn tile_size(&self) -> f32 {
+        self.tile_size
+    }
+
+    pub fn grid_to_world(&self, pos: &GridPos) -> Vec2 {
+        Vec2::new(
+            (pos.x - self.origin.x) as f32 * self.tile_size,
+            (pos.y - self.origin.y) as f32 * self.tile_size
+        )
+    }
+
+    pub fn world_to_grid(&self, pos: Vec2) -> GridPos {
+        GridPos {
+            x: (pos.x / self.tile_size).round() as i32 + self.origin.x,
+            y: (pos.y / self.tile_size).round() as i32 + self.origin.y,
+        }
+    }
+}
+
+impl LevelTiles {
+    pub fn grid_space(&self) -> GridSpace {
+        GridSpace::new(self.width, self.height, self.tile_size)
+    }
+
+    pub fn grid_to_world(&self, pos: GridPos) -> Vec2 {
+        self.grid_space().grid_to_world(&pos)
+    }
+
+    pub fn world_to_grid(&self, pos: Vec2) -> GridPos {
+        self.grid_space().world_to_grid(pos)
+    }
+
+    // False for walls, mirrors and anywhere off the edge of the level
+    pub fn is_walkable_at(&self, pos: Vec2) -> bool {
+        !self.get_tile(&self.world_to_grid(pos)).is_solid()
+    }
+
+    // Nothing but open tiles along the straight line between two points, sampled every quarter tile
+    pub fn line_of_sight(&self, from: Vec2, to: Vec2) -> bool {
+        let steps = (from.distance(to) / (0.25 * self.tile_size)).ceil() as i32;
+        for step in 0..=steps {
+            let t = if steps == 0 { 0.0 } else { step as f32 / steps as f32 };
+            if !self.is_walkable_at(from.lerp(to, t)) {
+                return false;
+            }
+        }
+        return true;
+    }
+
+    pub fn in_bounds(&self, pos: &GridPos) -> bool {
+        pos.x >= 0 && pos.y >= 0 && (pos.x as usize) < self.width && (pos.y as usize) < self.height
+    }
+
+    pub fn get_tile(&self, pos: &GridPos) -> TileValue {
+        if !self.in_bounds(pos) {
+             return TileValue::Wall; 
+        }
+
+        return self.tiles[pos.x as usize + (pos.y as usize * self.width)].clone();
+    }
+
+    fn successors(&self, pos: &GridPos) -> Vec<(GridPos, u32)> {
+        grid_successors(pos, |next| !self.get_tile(next).is_solid())
+    }
+
+    /*fn to_level_objects(&self) -> LevelObjects {
+
+    }*/
+}
+
+// Tiles one move away with what the move costs, diagonals only when neither side is blocked so corners aren't cut.
+// Shared by everything that paths over the grid so guards chasing, patrolling and searching all move the same way.
+pub fn grid_successors(pos: &GridPos, is_walkable: impl Fn(&GridPos) -> bool) -> Vec<(GridPos, u32)> {
+    let mut successors = Vec::<(GridPos, u32)>::with_capacity(8);
+    let mut step = |x: i32, y: i32, cost: u32| {
+        let next = GridPos{x: pos.x + x, y: pos.y + y};
+        let walkable = is_walkable(&next);
+        if walkable {
+            successors.push((next, cost));
+        }
+        walkable
+    };
+    let east = step(1, 0, STRAIGHT_COST);
+    let west = step(-1, 0, STRAIGHT_COST);
+    let north = step(0, 1, STRAIGHT_COST);
+    let south = step(0, -1, STRAIGHT_COST);
+    if east && north { step(1, 1, DIAGONAL_COST); }
+    if east && south { step(1, -1, DIAGONAL_COST); }
+    if west && north { step(-1, 1, DIAGONAL_COST); }
+    if west && south { step(-1, -1, DIAGONAL_COST); }
+    return successors;
+}
+
+fn get_tile_index(x: usize, y: usize, width: usize) -> usize {
+    x+ (y * width)
+}
+
+fn count_wall_continues_x(tiles: &Vec<bool>, width: usize, _height: usize, start: &IVec2) -> usize {
+    let mut x = start.x as usize;
+    let y = start.y as usize;
+    let mut cur_length = 0;
+    let mut index = get_tile_index(x, y, width);
+
+    while x < width && tiles[index] {
+        x += 1;
+        cur_length += 1;
+        index = get_tile_index(x, y, width); // May be same as +1 for now but safety
+    }
+
+    return cur_length;
+}
+
+fn count_wall_continues_y(tiles: &Vec<bool>, width: usize, height: usize, start: &IVec2) -> usize {
+    let x = start.x as usize;
+    let mut y = start.y as usize;
+    let mut cur_length = 0;
+    let mut index = get_tile_index(x, y, width);
+
+    while y < height && tiles[index] {
+        y += 1;
+        cur_length += 1;
+        index = get_tile_index(x, y, width);
+    }
+
+    return cur_length;
+}
+
+// Takes the biggest rectangle of wall tiles that has the root as its top left corner.
+// Each extra row down the column narrows the rectangle to the shortest run seen so far, ties go to the taller one.
+fn take_largest_wall(tiles: &mut Vec<bool>, width: usize, height: usize, root: &IVec2) -> Wall {
+    let column_length = count_wall_continues_y(&tiles, width, height, &root);
+
+    let mut best_width = 0;
+    let mut best_height = 0;
+    let mut run_width = usize::MAX;
+    for rows in 1..=column_length {
+        let row_start = IVec2::new(root.x, root.y + rows as i32 - 1);
+        run_width = usize::min(run_width, count_wall_continues_x(&tiles, width, height, &row_start));
+
+        if run_width * rows >= best_width * best_height {
+            best_width = run_width;
+            best_height = rows;
+        }
+    }
+
+    for y in root.y as usize..root.y as usize + best_height {
+        for x in root.x as usize..root.x as usize + best_width {
+            tiles[get_tile_index(x, y, width)] = false;
+        }
+    }
+
+    return Wall{top_left: root.clone(), bottom_right: IVec2::new(root.x + best_width as i32 - 1, root.y + best_height as i32 - 1)};
+}
+
+pub fn tile_vector_to_wall_set(tiles: &Vec<TileValue>, width: usize, height: usize) -> Vec<Wall> {
+    tile_vector_to_block_set(tiles, width, height, TileValue::Wall)
+}
+
+// Covers every tile of one kind with as few rectangles as it can
+pub fn tile_vector_to_block_set(tiles: &Vec<TileValue>, width: usize, height: usize, kind: TileValue) -> Vec<Wall> {
+    let mut remaining_wall_tiles = Vec::<bool>::new();
+    let mut walls = Vec::<Wall>::new();
+
+    for tile in tiles {
+        remaining_wall_tiles.push(*tile == kind);
+    }
+
+    for x in 0..width {
+        for y in 0..height {
+            let index = get_tile_index(x, y, width);
+
+            if remaining_wall_tiles[index] {
+                walls.push(take_largest_wall(&mut remaining_wall_tiles, width, height, &IVec2::new(x as i32, y as i32)));
+            }
+        }
+    }
+
+    return walls;
+}
+
+#[derive(Eq, Debug)]
+pub struct Wall {
+    top_left: IVec2,
+    bottom_right: IVec2,
+}
+
+impl Wall {
+    pub fn get_center(&self, tile_size: f32) -> Vec2 {
+        // Move out bottom right corner by 1 to include bottom right tile
+        let top = i32::max(self.top_left.y, self.bottom_right.y);
+        let bottom = i32::min(self.top_left.y, self.bottom_right.y);
+        let left = i32::min(self.top_left.x, self.bottom_right.x);
+        let right = i32::max(self.top_left.x, self.bottom_right.x);
+
+        let top_left = Vec2::new(left as f32, top as f32) * tile_size;
+        let bottom_right = Vec2::new(right as f32, bottom as f32) * tile_size;
+        return 0.5 * (top_left + bottom_right);
+    }
+
+    pub fn get_size(&self, tile_size: f32) -> Vec2 {
+        // Add 1 for inclusive range
+        let tile_height = (self.top_left.y - self.bottom_right.y).abs() + 1;
+        let tile_width = (self.top_left.x - self.bottom_right.x).abs() + 1;
+        return Vec2::new(tile_width as f32 * tile_size, tile_height as f32 * tile_size);
+    }
+}
+
+impl PartialEq for Wall {
+    fn eq(&self, other: &Wall) -> bool {
+        self.top_left == other.top_left && self.bottom_right == other.bottom_right
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wall_rect_single_tile() {
+        let wall = Wall{top_left: IVec2::new(0, 0), bottom_right: IVec2::new(0, 0)};
+        assert_eq!(wall.get_center(10.0), Vec2::new(0.0, 0.0));
+        assert_eq!(wall.get_size(10.0), Vec2::new(10.0, 10.0));
+    }
+
+    #[test]
+    fn test_wall_rect_long_x() {
+        let wall = Wall{top_left: IVec2::new(0, 0), bottom_right: IVec2::new(9, 0)};
+        assert_eq!(wall.get_center(10.0), Vec2::new(45.0, 0.0));
+        assert_eq!(wall.get_size(10.0), Vec2::new(100.0, 10.0));
+    }
+
+    #[test]
+    fn test_wall_rect_long_y() {
+        let wall = Wall{top_left: IVec2::new(0, 0), bottom_right: IVec2::new(0, 9)};
+        assert_eq!(wall.get_center(10.0), Vec2::new(0.0, 45.0));
+        assert_eq!(wall.get_size(10.0), Vec2::new(10.0, 100.0));
+    }
+
+    #[test]
+    fn test_wall_rect_square() {
+        let wall = Wall{top_left: IVec2::new(0, 0), bottom_right: IVec2::new(9, 9)};
+        assert_eq!(wall.get_center(10.0), Vec2::new(45.0, 45.0));
+        assert_eq!(wall.get_size(10.0), Vec2::new(100.0, 100.0));
+    }
+
+    #[test]
+    fn test_wall_x_length_single() {
+        let test_grid = vec![   true,  false, false, 
+                                false, false, false,
+                                false, false, false,
+                            ];
+        
+        assert_eq!(count_wall_continues_x(&test_grid, 3, 3, &IVec2::new(0, 0)), 1);
+    }
+
+    #[test]
+    fn test_wall_x_length_mid() {
+        let test_grid = vec![   true,  true,  false, 
+                                false, false, false,
+                                false, false, false,
+                            ];
+        
+        assert_eq!(count_wall_continues_x(&test_grid, 3, 3, &IVec2::new(0, 0)), 2);
+    }
+
+    #[test]
+    fn test_wall_x_length_wholeside() {
+        let test_grid = vec![   true,  true,  true, 
+                                false, false, false,
+                                false, false, false,
+                            ];
+        
+        assert_eq!(count_wall_continues_x(&test_grid, 3, 3, &IVec2::new(0, 0)), 3);
+    }
+
+    #[test]
+    fn test_wall_y_length_single() {
+        let test_grid = vec![   true,  false, false, 
+                                false, false, false,
+                                false, false, false,
+                            ];
+        
+        assert_eq!(count_wall_continues_y(&test_grid, 3, 3, &IVec2::new(0, 0)), 1);
+    }
+
+    #[test]
+    fn test_wall_y_length_mid() {
+        let test_grid = vec![   true,  false,  false, 
+                                true,  false,  false,
+                                false, false,  false,
+                            ];
+        
+        assert_eq!(count_wall_continues_y(&test_grid, 3, 3, &IVec2::new(0, 0)), 2);
+    }
+
+    #[test]
+    fn test_wall_y_length_wholeside() {
+        let test_grid = vec![   true,  false, false, 
+                                true,  false, false,
+                                true,  false, false,
+                            ];
+        
+        assert_eq!(count_wall_continues_y(&test_grid, 3, 3, &IVec2::new(0, 0)), 3);
+    }
+
+    #[test]
+    fn test_takes_longest_wall_x() {
+        let mut test_grid = vec![   true,  true,  true, 
+                                true,  false, false,
+                                false, false, false,
+                            ];
+        let expected_grid = vec![   false, false, false, 
+                                    true,  false, false,
+                                    false, false, false,
+                            ];
+        let expected_wall = Wall{top_left: IVec2::new(0, 0), bottom_right: IVec2::new(2, 0)};
+        
+        let result_wall = take_largest_wall(&mut test_grid, 3, 3, &IVec2::new(0, 0));
+        assert_eq!(result_wall.top_left, expected_wall.top_left, "Return correct wall top left bound");
+        assert_eq!(result_wall.bottom_right, expected_wall.bottom_right, "Return correct wall bottom right bound");
+        assert_eq!(test_grid, expected_grid, "Properly mutate grid");
+    }
+
+    #[test]
+    fn test_takes_longest_wall_y() {
+        let mut test_grid = vec![   true,  true, false, 
+                                    true,  false, false,
+                                    true,  false, false,
+                            ];
+        let expected_grid = vec![   false, true,  false, 
+                                    false, false, false,
+                                    false, false, false,
+                            ];
+        let expected_wall = Wall{top_left: IVec2::new(0, 0), bottom_right: IVec2::new(0, 2)};
+
+        let result_wall = take_largest_wall(&mut test_grid, 3, 3, &IVec2::new(0, 0));
+        assert_eq!(result_wall.top_left, expected_wall.top_left, "Return correct wall top left bound");
+        assert_eq!(result_wall.bottom_right, expected_wall.bottom_right, "Return correct wall bottom right bound");
+        assert_eq!(test_grid, expected_grid, "Properly mutate grid");
+    }
+
+    #[test]
+    fn test_makes_walls_steps() {
+        let test_grid = vec![   TileValue::Wall,  TileValue::Empty, TileValue::Empty, 
+                                    TileValue::Wall,  TileValue::Wall, TileValue::Empty,
+                                    TileValue::Wall,  TileValue::Wall, TileValue::Wall,
+                            ];
+        let expected_walls = vec! [
+            Wall{top_left: IVec2::new(0, 0), bottom_right: IVec2::new(0, 2)},
+            Wall{top_left: IVec2::new(1, 1), bottom_right: IVec2::new(1, 2)},   
+            Wall{top_left: IVec2::new(2, 2), bottom_right: IVec2::new(2, 2)},   
+        ];
+
+        let result_walls = tile_vector_to_wall_set(&test_grid, 3, 3);
+        
+        assert_eq!(result_walls.len(), 3, "3 walls expected");
+
+        for i in 0..3 {
+            assert_eq!(result_walls[i], expected_walls[i], "Wall {} matches", i);
+        }
+
+        assert_eq!(result_walls[0].get_center(10.0), Vec2::new(0.0, 10.0));
+        assert_eq!(result_walls[1].get_center(10.0), Vec2::new(10.0, 15.0));
+        assert_eq!(result_walls[2].get_center(10.0), Vec2::new(20.0, 20.0));
+    }
+
+    #[test]
+    fn test_makes_walls_empty() {
+        let test_grid = vec![   TileValue::Empty,  TileValue::Empty, TileValue::Empty, 
+                                TileValue::Empty,  TileValue::Empty, TileValue::Empty,
+                                TileValue::Empty,  TileValue::Empty, TileValue::Empty,
+                            ];
+        let result_walls = tile_vector_to_wall_set(&test_grid, 3, 3);
+        
+        assert_eq!(result_walls.len(), 0, "Empty grid should produce no walls");
+    }
+
+    #[test]
+    fn test_makes_walls_x() {
+        let test_grid = vec![   TileValue::Empty,  TileValue::Wall, TileValue::Empty, 
+                                TileValue::Wall,   TileValue::Wall, TileValue::Wall,
+                                TileValue::Empty,  TileValue::Wall, TileValue::Empty,
+                            ];
+        let expected_walls = vec! [
+            Wall{top_left: IVec2::new(0, 1), bottom_right: IVec2::new(2, 1)},
+            Wall{top_left: IVec2::new(1, 0), bottom_right: IVec2::new(1, 0)},   
+            Wall{top_left: IVec2::new(1, 2), bottom_right: IVec2::new(1, 2)},   
+        ];
+        let result_walls = tile_vector_to_wall_set(&test_grid, 3, 3);
+        
+        assert_eq!(result_walls.len(), 3, "3 walls expected");
+
+        for i in 0..3 {
+            assert_eq!(result_walls[i], expected_walls[i], "Wall {} matches", i);
+        }
+    }
+
+    // Tiles the rectangles don't cover exactly once if they're of the kind, or cover at all if they're not
+    fn miscovered_tiles(tiles: &Vec<TileValue>, width: usize, height: usize, walls: &Vec<Wall>, kind: TileValue) -> Vec<GridPos> {
+        let mut covered = vec![0; tiles.len()];
+        for wall in walls {
+            for y in wall.top_left.y..=wall.bottom_right.y {
+                for x in wall.top_left.x..=wall.bottom_right.x {
+                    covered[get_tile_index(x as usize, y as usize, width)] += 1;
+                }
+            }
+        }
+
+        let mut miscovered = Vec::<GridPos>::new();
+        for y in 0..height {
+            for x in 0..width {
+                let index = get_tile_index(x, y, width);
+                let expected = if tiles[index] == kind { 1 } else { 0 };
+                if covered[index] != expected {
+                    miscovered.push(GridPos{x: x as i32, y: y as i32});
+                }
+            }
+        }
+        return miscovered;
+    }
+
+    // Every wall tile is covered by exactly one wall and nothing else is
+    fn assert_walls_cover_tiles(tiles: &Vec<TileValue>, width: usize, height: usize, walls: &Vec<Wall>) {
+        let miscovered = miscovered_tiles(tiles, width, height, walls, TileValue::Wall);
+        assert!(miscovered.is_empty(), "Tiles covered wrongly: {:?}", miscovered);
+    }
+
+    #[test]
+    fn test_makes_walls_solid_block() {
+        let test_grid = vec![TileValue::Wall; 10 * 10];
+        let result_walls = tile_vector_to_wall_set(&test_grid, 10, 10);
+
+        assert_eq!(result_walls, vec![Wall{top_left: IVec2::new(0, 0), bottom_right: IVec2::new(9, 9)}]);
+    }
+
+    #[test]
+    fn test_makes_walls_2d_rectangles() {
+        let level = LevelTiles::parse("next\n\
+            ######\n\
+            ######\n\
+            ##  ##\n\
+            ##  ##\n\
+            ######\n").unwrap();
+        let result_walls = tile_vector_to_wall_set(&level.tiles, level.width, level.height);
+
+        // A ring of walls around a hole needs at least 4 rectangles
+        assert_eq!(result_walls.len(), 4);
+        assert_walls_cover_tiles(&level.tiles, level.width, level.height, &result_walls);
+    }
+
+    #[test]
+    fn test_walls_cover_same_area() {
+        let grids = [
+            "next\n# # #\n ### \n#####\n ### \n# # #\n",
+            "next\n#  ##\n## ##\n#####\n  # #\n### #\n",
+            // Solid block, L-shape and ring
+            "next\n    \n ## \n ## \n    \n",
+            "next\n#   \n#   \n#   \n####\n",
+            "next\n#####\n#   #\n# # #\n#   #\n#####\n",
+            // Rooms off a corridor, like a real level
+            "next\n##########\n#   #    #\n#   #  # #\n## ### # #\n#        #\n#### #####\n#  #     #\n#        #\n##########\n",
+        ];
+
+        for grid in grids.iter() {
+            let level = LevelTiles::parse(grid).unwrap();
+            let result_walls = tile_vector_to_wall_set(&level.tiles, level.width, level.height);
+            assert_walls_cover_tiles(&level.tiles, level.width, level.height, &result_walls);
+        }
+    }
+
+    #[test]
+    fn test_block_set_only_covers_its_kind() {
+        let level = LevelTiles::parse("next\n#####\n#%% #\n#%  #\n#####\n").unwrap();
+        let mirrors = tile_vector_to_block_set(&level.tiles, level.width, level.height, TileValue::Mirror);
+        assert!(miscovered_tiles(&level.tiles, level.width, level.height, &mirrors, TileValue::Mirror).is_empty());
+        assert_eq!(mirrors.len(), 2);
+
+        let walls = tile_vector_to_wall_set(&level.tiles, level.width, level.height);
+        assert_walls_cover_tiles(&level.tiles, level.width, level.height, &walls);
+    }
+
+    #[test]
+    fn test_is_walkable_at() {
+        let level = LevelTiles::parse("next\n#####\n# V #\n#####\n").unwrap();
+        // Tile (2, 1) is at the origin, tiles are 50 units apart
+        assert!(level.is_walkable_at(Vec2::new(0.0, 0.0)));
+        assert!(level.is_walkable_at(Vec2::new(-55.0, 10.0)));
+        assert!(!level.is_walkable_at(Vec2::new(0.0, 50.0)));
+        assert!(!level.is_walkable_at(Vec2::new(500.0, 0.0)));
+    }
+
+    #[test]
+    fn test_set_tile() {
+        let mut level = LevelTiles::parse("next\n#####\n#V $#\n#####\n").unwrap();
+
+        assert!(level.set_tile(&GridPos{x: 2, y: 1}, TileValue::Pickup));
+        assert_eq!(level.pickups_total, 2);
+        assert!(!level.set_tile(&GridPos{x: 2, y: 1}, TileValue::Pickup), "Same tile again is no change");
+        assert!(!level.set_tile(&GridPos{x: 5, y: 1}, TileValue::Wall), "Outside the level");
+
+        assert!(level.set_tile(&GridPos{x: 3, y: 1}, TileValue::Player));
+        assert_eq!(level.pickups_total, 1);
+        assert_eq!(level.to_level_string(), "next\n#####\n# $V#\n#####\n");
+    }
+
+    #[test]
+    fn test_cells_within() {
+        let level = LevelTiles::parse("next\n#######\n#V   ##\n### ###\n#######\n").unwrap();
+        let cells = level.cells_within(&GridPos{x: 1, y: 1}, 2);
+        let positions = cells.iter().map(|(pos, _steps)| pos.clone()).collect::<HashSet<GridPos>>();
+
+        assert_eq!(cells.len(), 3);
+        assert!(positions.contains(&GridPos{x: 3, y: 1}));
+        assert!(cells.contains(&(GridPos{x: 2, y: 1}, 1)));
+        assert!(level.cells_within(&GridPos{x: 0, y: 0}, 5).is_empty(), "Nothing is reachable from inside a wall");
+    }
+
+    #[test]
+    fn test_line_of_sight() {
+        let level = LevelTiles::parse("next\n#######\n#V   ##\n### ###\n#######\n").unwrap();
+        let left = level.grid_to_world(GridPos{x: 1, y: 1});
+        let right = level.grid_to_world(GridPos{x: 4, y: 1});
+        let above = level.grid_to_world(GridPos{x: 3, y: 1});
+        let below = level.grid_to_world(GridPos{x: 3, y: 2});
+
+        assert!(level.line_of_sight(left, right));
+        assert!(level.line_of_sight(above, below));
+        assert!(!level.line_of_sight(left, below), "Corner of the wall is in the way");
+    }
+
+    #[test]
+    fn test_parse_legacy_level() {
+        let level = LevelTiles::parse("next\n###\n#V#\n#$#\n").unwrap();
+        assert_eq!(level.next_level, "next");
+        assert_eq!(level.width, 3);
+        assert_eq!(level.height, 3);
+        assert_eq!(level.tile_size, DEFAULT_TILE_SIZE);
+        assert_eq!(level.pickups_total, 1);
+        assert_eq!(level.tiles[4], TileValue::Player);
+        assert_eq!(level.tiles[7], TileValue::Pickup);
+    }
+
+    #[test]
+    fn test_parse_legacy_skips_unknown_characters() {
+        let level = LevelTiles::parse("\n#?##\n# #\n").unwrap();
+        assert_eq!(level.width, 3);
+        assert_eq!(level.tiles.len(), 6);
+    }
+
+    #[test]
+    fn test_parse_structured_header() {
+        let text = "(\n    version: 2,\n    title: \"Vault\",\n    next_level: \"game\",\n    tile_size: 40.0,\n    par_time: Some(60.0),\n    ambient: Some(0.1),\n)\n---\n###\n#V#\n###\n";
+        let level = LevelTiles::parse(text).unwrap();
+        assert_eq!(level.title(), "Vault");
+        assert_eq!(level.next_level, "game");
+        assert_eq!(level.tile_size, 40.0);
+        assert_eq!(level.par_time(), Some(60.0));
+        assert_eq!(level.ambient(), Some(0.1));
+        assert_eq!(level.width, 3);
+        assert_eq!(level.height, 3);
+    }
+
+    #[test]
+    fn test_parse_structured_legend_and_enemy_params() {
+        let text = "(
+            version: 2,
+            enemy: (visual_range: Some(300.0), move_speed: Some(100.0)),
+            legend: {
+                'H': (tile: Enemy, enemy: (move_speed: Some(250.0))),
+                '=': (tile: Wall),
+            },
+        )
+---
+=====
+=VXH=
+=====
+";
+        let level = LevelTiles::parse(text).unwrap();
+        assert_eq!(level.tiles[0], TileValue::Wall);
+        assert_eq!(level.tiles[8], TileValue::Enemy);
+
+        let guard = level.enemy_params(7);
+        assert_eq!(guard.visual_range, Some(300.0));
+        assert_eq!(guard.move_speed, Some(100.0));
+
+        let hound = level.enemy_params(8);
+        assert_eq!(hound.visual_range, Some(300.0));
+        assert_eq!(hound.move_speed, Some(250.0));
+        assert_eq!(hound.vision_cone_angle, None);
+    }
+
+    #[test]
+    fn test_parse_lamps() {
+        let text = "(
+            version: 2,
+            lamp: (color: Some((1.0, 0.8, 0.5)), reach: Some(300.0)),
+            legend: {
+                'F': (tile: Lamp, lamp: (reach: Some(150.0), flicker: Some(0.5))),
+            },
+        )
+---
+#####
+#V*F#
+#####
+";
+        let level = LevelTiles::parse(text).unwrap();
+        assert_eq!(level.tiles[7], TileValue::Lamp);
+        assert_eq!(level.tiles[8], TileValue::Lamp);
+
+        let lamp = level.lamp_params(7);
+        assert_eq!(lamp.reach, Some(300.0));
+        assert_eq!(lamp.flicker, None);
+
+        let flickering = level.lamp_params(8);
+        assert_eq!(flickering.color, Some([1.0, 0.8, 0.5]));
+        assert_eq!(flickering.reach, Some(150.0));
+        assert_eq!(flickering.flicker, Some(0.5));
+
+        // Lamps are floor, so they don't get in the way
+        assert!(level.is_walkable_at(level.grid_to_world(GridPos{x: 2, y: 1})));
+        assert_eq!(LevelTiles::parse(&level.to_level_string()).unwrap().lamp_params(8), flickering);
+    }
+
+    #[test]
+    fn test_parse_structured_rejects_unknown_characters() {
+        assert!(LevelTiles::parse("(version: 2)\n---\n#?#\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_structured_rejects_bad_header() {
+        assert!(LevelTiles::parse("(version: 1)\n---\n###\n").is_err(), "Wrong version");
+        assert!(LevelTiles::parse("(version: 2, colour: 3)\n---\n###\n").is_err(), "Unknown field");
+        assert!(LevelTiles::parse("(version: 2)\n###\n").is_err(), "Missing header end");
+    }
+
+    fn errors(check: &LevelCheck) -> Vec<(usize, usize)> {
+        check.diagnostics.iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Error)
+            .map(|diagnostic| (diagnostic.line, diagnostic.column))
+            .collect()
+    }
+
+    #[test]
+    fn test_check_valid_level() {
+        let check = check_level("next\n#####\n#V $#\n#####\n");
+        assert!(check.diagnostics.is_empty(), "{:?}", check.diagnostics);
+        assert!(check.into_level("valid.level").is_ok());
+    }
+
+    #[test]
+    fn test_check_ragged_rows() {
+        let check = check_level("next\n#####\n#V $##\n###\n");
+        assert_eq!(errors(&check), vec![(3, 6), (4, 4)]);
+        assert!(check.into_level("ragged.level").is_err());
+    }
+
+    #[test]
+    fn test_check_player_spawn_count() {
+        let none = check_level("next\n####\n#  #\n####\n");
+        assert_eq!(errors(&none), vec![(2, 1)]);
+
+        let several = check_level("next\n#####\n#V V#\n#V  #\n#####\n");
+        assert_eq!(errors(&several), vec![(3, 4), (4, 2)]);
+    }
+
+    #[test]
+    fn test_check_unreachable_pickup() {
+        let check = check_level("next\n#######\n#V #$ #\n#######\n");
+        assert_eq!(errors(&check), vec![(3, 5)]);
+        assert_eq!(check.level.unwrap().unreachable_pickups(), vec![GridPos{x: 4, y: 1}]);
+    }
+
+    #[test]
+    fn test_check_diagonal_needs_open_corner() {
+        // Successors don't allow cutting across the corner between two walls
+        let check = check_level("next\n####\n#V##\n##$#\n####\n");
+        assert_eq!(errors(&check), vec![(4, 3)]);
+    }
+
+    #[test]
+    fn test_check_unknown_characters() {
+        let legacy = check_level("next\n####\n#V?$#\n####\n");
+        assert_eq!(legacy.diagnostics[0].severity, Severity::Warning);
+        assert_eq!((legacy.diagnostics[0].line, legacy.diagnostics[0].column), (3, 3));
+        assert!(!legacy.has_errors());
+
+        let structured = check_level("(version: 2)\n---\n####\n#V?#\n####\n");
+        assert_eq!(errors(&structured), vec![(4, 3)]);
+    }
+
+    #[test]
+    fn test_parse_waypoints() {
+        let level = LevelTiles::parse("next\n#####\n#V19#\n#####\n").unwrap();
+        assert_eq!(level.tiles[7], TileValue::Waypoint(1));
+        assert_eq!(level.tiles[8], TileValue::Waypoint(9));
+        assert_eq!(level.to_level_string(), "next\n#####\n#V19#\n#####\n");
+    }
+
+    #[test]
+    fn test_patrol_routes_use_nearest_guard() {
+        let level = LevelTiles::parse("next\n##########\n#2X1##3X4#\n#V  ##   #\n##########\n").unwrap();
+        let routes = level.patrol_routes();
+
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[&12], vec![GridPos{x: 3, y: 1}, GridPos{x: 1, y: 1}]);
+        assert_eq!(routes[&17], vec![GridPos{x: 6, y: 1}, GridPos{x: 8, y: 1}]);
+    }
+
+    #[test]
+    fn test_check_unassigned_waypoint() {
+        let check = check_level("next\n######\n#VX#5#\n######\n");
+        assert!(!check.has_errors());
+        assert_eq!(check.diagnostics.len(), 1);
+        assert_eq!(check.diagnostics[0].severity, Severity::Warning);
+        assert_eq!((check.diagnostics[0].line, check.diagnostics[0].column), (3, 5));
+    }
+
+    #[test]
+    fn test_check_header_error_position() {
+        let check = check_level("(\n    version: 2,\n    tile_size: \"big\",\n)\n---\n###\n");
+        assert!(check.level.is_none());
+        assert_eq!(check.diagnostics[0].line, 3);
+    }
+
+    #[test]
+    fn test_check_report_names_file() {
+        let check = check_level("next\n####\n#  #\n####\n");
+        assert_eq!(check.report("levels/empty.level"), "levels/empty.level:2:1: error: Level has no player spawn");
+    }
+
+    #[test]
+    fn test_get_tile_out_of_bounds() {
+        let level = LevelTiles::parse("next\n  \n  \n").unwrap();
+        assert!(level.get_tile(&GridPos{x: 1, y: 1}) == TileValue::Empty);
+        assert!(level.get_tile(&GridPos{x: 2, y: 0}) == TileValue::Wall);
+        assert!(level.get_tile(&GridPos{x: 0, y: 2}) == TileValue::Wall);
+        assert!(level.get_tile(&GridPos{x: -1, y: 0}) == TileValue::Wall);
+    }
+}
\ No newline at end of file
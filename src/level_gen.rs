@@ -0,0 +1,319 @@
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use std::collections::VecDeque;
+
+use crate::level_data::{LevelTiles, TileValue};
+
+// Tuning for generated levels, distances are in tiles walked from the player spawn
+pub struct GenParams {
+    pub width: usize,
+    pub height: usize,
+    pub min_room_size: usize,
+    pub max_room_size: usize,
+    pub pickups: usize,
+    pub enemies: usize,
+    pub min_pickup_distance: u32,
+    pub min_enemy_distance: u32,
+}
+
+impl Default for GenParams {
+    fn default() -> GenParams {
+        GenParams {
+            width: 40,
+            height: 40,
+            min_room_size: 4,
+            max_room_size: 10,
+            pickups: 20,
+            enemies: 5,
+            min_pickup_distance: 4,
+            min_enemy_distance: 12,
+        }
+    }
+}
+
+impl GenParams {
+    // Levels get bigger and better guarded the further into an endless run the player gets
+    pub fn endless(depth: u32) -> GenParams {
+        let depth = depth as usize;
+        GenParams {
+            width: usize::min(30 + depth * 4, 70),
+            height: usize::min(30 + depth * 4, 70),
+            pickups: usize::min(10 + depth * 2, 40),
+            enemies: usize::min(2 + depth, 14),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Area {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Area {
+    fn center(&self) -> (usize, usize) {
+        (self.x + self.width / 2, self.y + self.height / 2)
+    }
+}
+
+struct Generator<'a> {
+    params: &'a GenParams,
+    rng: StdRng,
+    width: usize,
+    height: usize,
+    floor: Vec<bool>,
+    rooms: Vec<Area>,
+}
+
+// Builds a level of rooms joined by corridors by splitting the map into a BSP tree.
+// The same seed and params always produce the same level.
+pub fn generate_level(seed: u64, params: &GenParams) -> LevelTiles {
+    let mut generator = Generator::new(seed, params);
+    generator.split(Area { x: 0, y: 0, width: generator.width, height: generator.height });
+    generator.populate()
+}
+
+impl<'a> Generator<'a> {
+    fn new(seed: u64, params: &'a GenParams) -> Generator<'a> {
+        // Needs to be big enough for at least one room inside the border
+        let width = params.width.max(params.min_room_size + 2);
+        let height = params.height.max(params.min_room_size + 2);
+
+        Generator {
+            params,
+            rng: StdRng::seed_from_u64(seed),
+            width,
+            height,
+            floor: vec![false; width * height],
+            rooms: vec![],
+        }
+    }
+
+    // Returns a floor tile inside the area to connect corridors to
+    fn split(&mut self, area: Area) -> (usize, usize) {
+        // Leaves keep a wall tile either side of their room so neighbouring rooms never merge
+        let min_leaf = self.params.min_room_size + 2;
+        let max_leaf = self.params.max_room_size + 2;
+        let can_split_x = area.width >= min_leaf * 2;
+        let can_split_y = area.height >= min_leaf * 2;
+        let too_big = area.width > max_leaf || area.height > max_leaf;
+
+        if (!can_split_x && !can_split_y) || (!too_big && self.rng.gen_bool(0.3)) {
+            return self.carve_room(area);
+        }
+
+        let split_x = if can_split_x && can_split_y {
+            area.width > area.height || (area.width == area.height && self.rng.gen())
+        }
+        else {
+            can_split_x
+        };
+
+        let (first, second) = if split_x {
+            let at = self.rng.gen_range(min_leaf..=area.width - min_leaf);
+            (
+                Area { width: at, ..area },
+                Area { x: area.x + at, width: area.width - at, ..area },
+            )
+        }
+        else {
+            let at = self.rng.gen_range(min_leaf..=area.height - min_leaf);
+            (
+                Area { height: at, ..area },
+                Area { y: area.y + at, height: area.height - at, ..area },
+            )
+        };
+
+        let first_point = self.split(first);
+        let second_point = self.split(second);
+        self.carve_corridor(first_point, second_point);
+
+        if self.rng.gen() { first_point } else { second_point }
+    }
+
+    fn carve_room(&mut self, leaf: Area) -> (usize, usize) {
+        let max_width = usize::min(self.params.max_room_size, leaf.width - 2);
+        let max_height = usize::min(self.params.max_room_size, leaf.height - 2);
+        let width = self.rng.gen_range(usize::min(self.params.min_room_size, max_width)..=max_width);
+        let height = self.rng.gen_range(usize::min(self.params.min_room_size, max_height)..=max_height);
+
+        let room = Area {
+            x: leaf.x + self.rng.gen_range(1..=leaf.width - width - 1),
+            y: leaf.y + self.rng.gen_range(1..=leaf.height - height - 1),
+            width,
+            height,
+        };
+
+        for y in room.y..room.y + room.height {
+            for x in room.x..room.x + room.width {
+                self.set_floor(x, y);
+            }
+        }
+
+        self.rooms.push(room);
+        room.center()
+    }
+
+    // Two tile wide L shaped corridor between two points
+    fn carve_corridor(&mut self, from: (usize, usize), to: (usize, usize)) {
+        let corner = if self.rng.gen() { (to.0, from.1) } else { (from.0, to.1) };
+
+        for (start, end) in [(from, corner), (corner, to)].iter() {
+            for x in usize::min(start.0, end.0)..=usize::max(start.0, end.0) {
+                for y in usize::min(start.1, end.1)..=usize::max(start.1, end.1) {
+                    self.set_floor(x, y);
+                    self.set_floor(x + 1, y);
+                    self.set_floor(x, y + 1);
+                    self.set_floor(x + 1, y + 1);
+                }
+            }
+        }
+    }
+
+    // Never carves the outer border
+    fn set_floor(&mut self, x: usize, y: usize) {
+        if x > 0 && y > 0 && x < self.width - 1 && y < self.height - 1 {
+            self.floor[x + y * self.width] = true;
+        }
+    }
+
+    // Walking distance from the start to every floor tile, None where it can't be reached
+    fn distances_from(&self, start: usize) -> Vec<Option<u32>> {
+        let mut distances = vec![None; self.floor.len()];
+        let mut frontier = VecDeque::<usize>::new();
+        distances[start] = Some(0);
+        frontier.push_back(start);
+
+        while let Some(index) = frontier.pop_front() {
+            let distance = distances[index].unwrap();
+            let neighbours = [index - 1, index + 1, index - self.width, index + self.width];
+            for next in neighbours.iter() {
+                if self.floor[*next] && distances[*next].is_none() {
+                    distances[*next] = Some(distance + 1);
+                    frontier.push_back(*next);
+                }
+            }
+        }
+
+        return distances;
+    }
+
+    fn populate(mut self) -> LevelTiles {
+        let spawn_room = *self.rooms.choose(&mut self.rng).expect("Generator always makes at least one room");
+        let (spawn_x, spawn_y) = spawn_room.center();
+        let spawn = spawn_x + spawn_y * self.width;
+
+        let distances = self.distances_from(spawn);
+
+        // Should never happen since every split is joined up, but anything the player can't walk to gets filled in
+        for index in 0..self.floor.len() {
+            if distances[index].is_none() {
+                self.floor[index] = false;
+            }
+        }
+
+        let mut tiles = self.floor.iter()
+            .map(|floor| if *floor { TileValue::Empty } else { TileValue::Wall })
+            .collect::<Vec<TileValue>>();
+        tiles[spawn] = TileValue::Player;
+
+        let mut pickup_spots = (0..tiles.len())
+            .filter(|index| tiles[*index] == TileValue::Empty)
+            .filter(|index| distances[*index].map_or(false, |distance| distance >= self.params.min_pickup_distance))
+            .collect::<Vec<usize>>();
+        pickup_spots.shuffle(&mut self.rng);
+        for index in pickup_spots.into_iter().take(self.params.pickups) {
+            tiles[index] = TileValue::Pickup;
+        }
+
+        let mut enemy_spots = (0..tiles.len())
+            .filter(|index| tiles[*index] == TileValue::Empty)
+            .filter(|index| distances[*index].map_or(false, |distance| distance >= self.params.min_enemy_distance))
+            .collect::<Vec<usize>>();
+        enemy_spots.shuffle(&mut self.rng);
+
+        let mut enemies = Vec::<usize>::new();
+        for index in enemy_spots {
+            if enemies.len() >= self.params.enemies {
+                break;
+            }
+            // Keep guards from spawning on top of each other
+            let (x, y) = (index % self.width, index / self.width);
+            let crowded = enemies.iter().any(|other| {
+                let (other_x, other_y) = (other % self.width, other / self.width);
+                x.max(other_x) - x.min(other_x) + y.max(other_y) - y.min(other_y) < 3
+            });
+            if !crowded {
+                enemies.push(index);
+                tiles[index] = TileValue::Enemy;
+            }
+        }
+
+        LevelTiles::new(self.width, self.height, tiles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::level_data::{check_level, GridPos};
+
+    #[test]
+    fn test_generated_levels_are_valid() {
+        for seed in 0..20 {
+            let level = generate_level(seed, &GenParams::default());
+            let check = check_level(&level.to_level_string());
+            assert!(!check.has_errors(), "Seed {}:\n{}", seed, check.report("generated"));
+        }
+    }
+
+    #[test]
+    fn test_generated_floor_is_connected() {
+        for seed in 0..20 {
+            let level = generate_level(seed, &GenParams::endless(seed as u32));
+            let spawn = level.player_spawn().expect("Generated level has a spawn");
+            let reachable = level.reachable_from(&spawn);
+
+            for y in 0..level.height {
+                for x in 0..level.width {
+                    let pos = GridPos{x: x as i32, y: y as i32};
+                    if level.tiles[x + y * level.width] != TileValue::Wall {
+                        assert!(reachable.contains(&pos), "Seed {} tile {:?} is cut off", seed, pos);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_generated_placement_respects_distances() {
+        let params = GenParams { pickups: 10, enemies: 4, min_pickup_distance: 6, min_enemy_distance: 15, ..Default::default() };
+        let level = generate_level(7, &params);
+
+        // Rebuild the same layout to measure distances on it
+        let mut generator = Generator::new(7, &params);
+        generator.split(Area { x: 0, y: 0, width: params.width, height: params.height });
+
+        let spawn = level.player_spawn().unwrap();
+        let distances = generator.distances_from(spawn.x as usize + spawn.y as usize * params.width);
+        for (index, tile) in level.tiles.iter().enumerate() {
+            match tile {
+                TileValue::Pickup => assert!(distances[index].unwrap() >= 6),
+                TileValue::Enemy => assert!(distances[index].unwrap() >= 15),
+                _ => (),
+            }
+        }
+        assert_eq!(level.tiles.iter().filter(|tile| **tile == TileValue::Pickup).count(), 10);
+    }
+
+    #[test]
+    fn test_generation_is_deterministic() {
+        let first = generate_level(1234, &GenParams::default()).to_level_string();
+        let second = generate_level(1234, &GenParams::default()).to_level_string();
+        let other = generate_level(4321, &GenParams::default()).to_level_string();
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+    }
+}
@@ -0,0 +1,134 @@
+use bevy::{asset::LoadState, prelude::*};
+
+use crate::gamestate::{CurrentLevel, GameState};
+use crate::level_data::LevelTiles;
+
+pub struct LevelSelectPlugin;
+
+impl Plugin for LevelSelectPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app
+            .insert_resource(LevelList{levels: vec![]})
+            .add_system_set(SystemSet::on_enter(GameState::Startup)
+                .with_system(level_select_setup.system())
+            )
+            .add_system_set(SystemSet::on_update(GameState::Startup)
+                .with_system(level_select_keyboard.system())
+                .with_system(level_select_text_system.system())
+            )
+        ;
+    }
+}
+
+// Every level in assets/levels by name, the handles keep them loaded so sizes can be shown
+pub struct LevelList {
+    levels: Vec<(String, HandleUntyped)>,
+}
+
+struct LevelSelectText;
+
+fn level_select_setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut level_list: ResMut<LevelList>,
+) {
+    match asset_server.load_folder("levels") {
+        Ok(handles) => {
+            let mut levels = handles.into_iter()
+                .filter_map(|handle| {
+                    let path = asset_server.get_handle_path(&handle)?;
+                    if path.path().extension().map_or(true, |extension| extension != "level") {
+                        return None;
+                    }
+                    let name = path.path().file_stem()?.to_string_lossy().to_string();
+                    Some((name, handle))
+                })
+                .collect::<Vec<(String, HandleUntyped)>>();
+            levels.sort_by(|a, b| a.0.cmp(&b.0));
+            level_list.levels = levels;
+        }
+        Err(err) => println!("Couldn't list levels: {:?}", err),
+    }
+
+    commands.spawn_bundle(TextBundle {
+        text: Text {
+            sections: vec![
+                TextSection {
+                    value: "Levels".to_string(),
+                    style: TextStyle {
+                        font: asset_server.load("fonts/Roboto-Regular.ttf"),
+                        font_size: 40.0,
+                        color: Color::rgb(0.6, 0.6, 1.0)
+                    },
+                },
+                TextSection {
+                    value: "".to_string(),
+                    style: TextStyle {
+                        font: asset_server.load("fonts/Roboto-Regular.ttf"),
+                        font_size: 30.0,
+                        color: Color::rgb(0.4, 0.4, 1.0)
+                    },
+                },
+            ],
+            ..Default::default()
+        },
+        style: Style {
+            position_type: PositionType::Absolute,
+            position: Rect {
+                top: Val::Px(5.0),
+                right: Val::Px(15.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .insert(LevelSelectText);
+}
+
+// Picking a level changes CurrentLevel straight away, starting or editing uses whatever is picked
+fn level_select_keyboard(
+    keyboard_input: Res<Input<KeyCode>>,
+    level_list: Res<LevelList>,
+    mut current_level: ResMut<CurrentLevel>,
+) {
+    let count = level_list.levels.len();
+    if count == 0 { return; }
+
+    let current = level_list.levels.iter().position(|(name, _)| *name == current_level.name);
+    let selected = if keyboard_input.just_pressed(KeyCode::Up) {
+        current.map_or(count - 1, |index| (index + count - 1) % count)
+    }
+    else if keyboard_input.just_pressed(KeyCode::Down) {
+        current.map_or(0, |index| (index + 1) % count)
+    }
+    else {
+        return;
+    };
+
+    *current_level = CurrentLevel::new(&level_list.levels[selected].0);
+}
+
+fn level_select_text_system(
+    asset_server: Res<AssetServer>,
+    levels: Res<Assets<LevelTiles>>,
+    level_list: Res<LevelList>,
+    current_level: Res<CurrentLevel>,
+    mut query: Query<&mut Text, With<LevelSelectText>>,
+) {
+    let mut list_text = String::new();
+    for (name, handle) in level_list.levels.iter() {
+        let marker = if *name == current_level.name { ">" } else { " " };
+        let details = match levels.get(handle) {
+            Some(level) if !level.title().is_empty() => format!("{} - {}x{}", level.title(), level.width, level.height),
+            Some(level) => format!("{}x{}", level.width, level.height),
+            None if asset_server.get_load_state(handle) == LoadState::Failed => "can't be loaded".to_string(),
+            None => "loading".to_string(),
+        };
+        list_text += &format!("\n{} {} ({})", marker, name, details);
+    }
+
+    for mut text in query.iter_mut() {
+        text.sections[1].value = list_text.clone();
+    }
+}
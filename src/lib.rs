@@ -0,0 +1,2 @@
+// Level file parsing and checking, shared by the game and the level-check tool
+pub mod level_data;
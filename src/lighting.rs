@@ -1,9 +1,14 @@
-use bevy::{math::Vec3Swizzles, prelude::*, render::{pipeline::{BlendOperation, PipelineDescriptor}, shader::{ShaderStage, ShaderStages}}, tasks::ComputeTaskPool};
+use bevy::{math::Vec3Swizzles, prelude::*, render::{pipeline::{BlendFactor, BlendOperation, BlendState, CompareFunction, CullMode, PipelineDescriptor}, shader::{ShaderStage, ShaderStages}}, tasks::ComputeTaskPool};
+use geo::algorithm::contains::Contains;
 use geo::coords_iter::CoordsIter;
 use geo::{Polygon,};
 
 use crate::{level};
 use crate::ai::Facing;
+use crate::exposure::{light_falloff, light_strength, LightExposure};
+use crate::gamestate::GameState;
+use crate::level_data::LampParams;
+use crate::mirror::{reflected_light, Mirror, Mirrors, REFLECTION_RAYS};
 use crate::visibility::VisChecker;
 
 pub struct LightingPlugin;
@@ -15,9 +20,14 @@ impl Plugin for LightingPlugin {
                 pipeline_handle: None,
                 base_mesh: None
             })
+            .insert_resource(AmbientLight{level: DEFAULT_AMBIENT})
+            .insert_resource(LightExposure::default())
+            .insert_resource(Mirrors::default())
             .add_startup_system(light_setup_system.system().label("graphics_init"))
+            .add_system(ambient_light_system.system())
             .add_system(point_light_mesh_builder.system().label("light_build").after("light_setup"))
-            .add_system(spotlight_mesh_builder.system().after("light_setup"))
+            .add_system(spotlight_mesh_builder.system().label("light_build").after("light_setup"))
+            .add_system(light_exposure_system.system().after("light_build"))
             .add_system(test_spin_system.system())
             .add_system(dynamic_light_blocking_system.system().label("light_setup"))
             .add_system(light_mesh_applicator.system().after("light_build"))
@@ -30,25 +40,150 @@ pub struct LightRenderData {
     pub base_mesh: Option<Mesh>
 }
 
+// The scene is only as bright as the light falling on it. Every light adds its brightness to the alpha channel
+// on top of the ambient level, then the whole screen is multiplied by it, see DARKNESS_FRAGMENT_SHADER.
+pub struct AmbientLight {
+    // How bright places no light reaches are, from 0 for pitch black to 1 for fully lit
+    pub level: f32,
+}
+
+pub const DEFAULT_AMBIENT: f32 = 0.25;
+
+// Screen covering quad that starts the light buffer off at the ambient level. Drawn above everything in the scene
+// and below every light, the one that multiplies the scene by the light buffer is drawn above every light.
+pub struct AmbientQuad;
+const AMBIENT_QUAD_Z: f32 = 0.02;
+const COMPOSITE_QUAD_Z: f32 = 0.5;
+
+// Light the player carries around so they can always see their feet, guards don't count it as the player being lit
+pub struct PersonalLight;
+const PERSONAL_LIGHT_COLOR: [f32; 3] = [0.3, 0.3, 0.35];
+
 pub struct PointLight {
     mesh_built: bool,
     pub color: Color,
-    pub reach: f32
+    pub reach: f32,
+    // How far the brightness dips when it flickers, 0 for a steady light
+    pub flicker: f32,
+    // Seconds for one slow fade down and back up
+    pub pulse_period: Option<f32>,
+    // Brightness this frame after flicker and pulse, from 0 to 1
+    brightness: f32,
+    // Only shadows from the level's walls, for lights carried by something that blocks light itself
+    pub static_shadows: bool,
+    // The shadows only get worked out again when the light moves or the temporary blockers within reach change,
+    // see LevelGeo::temp_blocks_near
+    built_center: Vec2,
+    nearby_blockers: Vec<geo::Rect<f64>>,
+    vis_polygon: Option<Polygon<f64>>,
 }
 
+// Lamps placed by a level are dim and warm unless it says otherwise
+const LAMP_COLOR: [f32; 3] = [1.0, 0.85, 0.6];
+const LAMP_REACH: f32 = 250.0;
+// Flicker only dips the light when its waves line up above this, so it's steady most of the time
+const FLICKER_THRESHOLD: f32 = 0.2;
+// Brightness at the bottom of a pulse
+const PULSE_LOW: f32 = 0.4;
+// Anything standing in the light blocks it too, so points are tested this far in front of them towards the light
+const SHADOW_TEST_INSET: f32 = 15.0;
+// Inner and outer angle given to lights without a cone, wider than any angle so it lights all the way around
+const NO_CONE_ANGLE: f32 = 4.0;
+
 impl PointLight {
-    pub fn _new(color: Color, reach: f32) -> PointLight {
-        PointLight{mesh_built: false, color, reach}
+    pub fn new(color: Color, reach: f32) -> PointLight {
+        PointLight {
+            mesh_built: false,
+            color,
+            reach,
+            flicker: 0.0,
+            pulse_period: None,
+            brightness: 1.0,
+            static_shadows: false,
+            built_center: Vec2::default(),
+            nearby_blockers: vec![],
+            vis_polygon: None,
+        }
+    }
+
+    pub fn with_static_shadows(mut self) -> PointLight {
+        self.static_shadows = true;
+        self
+    }
+
+    pub fn with_flicker(mut self, flicker: f32) -> PointLight {
+        self.flicker = flicker.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_pulse(mut self, period: f32) -> PointLight {
+        self.pulse_period = if period > 0.0 { Some(period) } else { None };
+        self
+    }
+
+    // Seed keeps lights next to each other from flickering in step
+    fn brightness_at(&self, time: f32, seed: f32) -> f32 {
+        let pulse = match self.pulse_period {
+            Some(period) => 1.0 - (1.0 - PULSE_LOW) * 0.5 * (1.0 - (std::f32::consts::TAU * time / period).cos()),
+            None => 1.0,
+        };
+
+        let t = time + seed;
+        let waves = (t * 13.0).sin() * (t * 7.3).sin() * (t * 2.9).sin();
+        let dip = ((waves - FLICKER_THRESHOLD) / (1.0 - FLICKER_THRESHOLD)).max(0.0);
+
+        return pulse * (1.0 - self.flicker * dip);
+    }
+
+    // How brightly this light falls on a point, the same as the shader draws it. 0 for points in its shadow.
+    pub fn light_at(&self, center: Vec2, point: Vec2) -> f32 {
+        match &self.vis_polygon {
+            Some(polygon) if in_light(polygon, center, point) => {
+                light_falloff(center.distance(point), self.reach, 0.0, NO_CONE_ANGLE, NO_CONE_ANGLE)
+                    * light_strength(self.color) * self.brightness
+            },
+            _ => 0.0,
+        }
     }
 }
 
+// Whether a point is inside the visibility polygon cast from center
+fn in_light(polygon: &Polygon<f64>, center: Vec2, point: Vec2) -> bool {
+    let test_point = point + (center - point).try_normalize().unwrap_or_default() * SHADOW_TEST_INSET.min(center.distance(point));
+    polygon.contains(&geo::Point::new(test_point.x as f64, test_point.y as f64))
+}
+
 pub struct SpotLight {
     mesh_built: bool,
     pub color: Color,
-    pub angle: f32,
-    pub reach: f32
+    // Full brightness within the inner angle of the facing, fading out to nothing at the outer angle, in radians.
+    // Guards use their vision cone as the outer angle so anything lit at all is something they can see.
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    pub reach: f32,
+    // Width of the soft band along shadow edges, 0 for hard shadows
+    pub softness: f32,
+    // What was last drawn, kept for working out how lit the player is
+    lit_areas: Vec<LitArea>,
+}
+
+// One penumbra sample of a spotlight or its reflection in a mirror
+struct LitArea {
+    polygon: Polygon<f64>,
+    // Where the shadows were cast from
+    cast_from: Vec2,
+    // Where the cone and distance falloff are measured from, the mirrored light origin for a reflection
+    light_pos: Vec2,
+    facing: f32,
 }
 
+// How much of the cone is at full brightness unless set otherwise
+const DEFAULT_INNER_FRACTION: f32 = 0.6;
+const DEFAULT_SOFTNESS: f32 = 8.0;
+// Soft shadows come from drawing the light from this many points spread across the softness, each a share
+// of the brightness, so only places all of them reach are fully lit
+const PENUMBRA_SAMPLES: usize = 3;
+
 
 #[derive(Default)]
 pub struct LightMeshData {
@@ -58,13 +193,75 @@ pub struct LightMeshData {
     v_lightpower: Vec<f32>,
     v_lightfacing: Vec<f32>,
     v_lightangle: Vec<f32>,
+    v_lightinnerangle: Vec<f32>,
+    v_lightsamples: Vec<f32>,
     indices: Vec<u32>,
     refresh_data: bool,
 }
 
+impl LightMeshData {
+    fn push_vertex(&mut self, point: Vec2, z: f32, color: Color, light_pos: Vec2, reach: f32, facing: f32, inner_angle: f32, outer_angle: f32, samples: usize) {
+        self.v_pos.push([point.x, point.y, z]);
+        self.v_color.push([color.r(), color.g(), color.b()]);
+        self.v_lightpos.push([light_pos.x, light_pos.y, z]);
+        self.v_lightpower.push(reach);
+        self.v_lightfacing.push(facing);
+        self.v_lightangle.push(outer_angle);
+        self.v_lightinnerangle.push(inner_angle);
+        self.v_lightsamples.push(samples as f32);
+    }
+
+    fn clear(&mut self) {
+        self.v_pos.clear();
+        self.v_color.clear();
+        self.v_lightpos.clear();
+        self.v_lightpower.clear();
+        self.v_lightfacing.clear();
+        self.v_lightangle.clear();
+        self.v_lightinnerangle.clear();
+        self.v_lightsamples.clear();
+        self.indices.clear();
+    }
+}
+
 impl SpotLight {
     pub fn new(angle: f32, color: Color, reach: f32) -> SpotLight {
-        SpotLight{mesh_built: false, color, angle, reach}
+        SpotLight {
+            mesh_built: false,
+            color,
+            inner_angle: angle * DEFAULT_INNER_FRACTION,
+            outer_angle: angle,
+            reach,
+            softness: DEFAULT_SOFTNESS,
+            lit_areas: vec![],
+        }
+    }
+
+    pub fn with_inner_angle(mut self, inner_angle: f32) -> SpotLight {
+        self.inner_angle = inner_angle.min(self.outer_angle);
+        self
+    }
+
+    pub fn with_softness(mut self, softness: f32) -> SpotLight {
+        self.softness = softness;
+        self
+    }
+
+    // How brightly this light falls on a point, the same as the shader draws it
+    pub fn light_at(&self, point: Vec2) -> f32 {
+        let lit: f32 = self.lit_areas.iter()
+            .filter(|area| in_light(&area.polygon, area.cast_from, point))
+            .map(|area| {
+                let forward = Vec2::new(area.facing.cos(), area.facing.sin());
+                let angle = (point - area.light_pos).try_normalize().map_or(0.0, |direction| forward.angle_between(direction).abs());
+                light_falloff(area.light_pos.distance(point), self.reach, angle, self.inner_angle, self.outer_angle)
+            })
+            .sum();
+        return lit * light_strength(self.color) / self.samples() as f32;
+    }
+
+    fn samples(&self) -> usize {
+        if self.softness > 0.0 { PENUMBRA_SAMPLES } else { 1 }
     }
 }
 
@@ -95,48 +292,112 @@ pub fn dynamic_light_blocking_system(
     }
 }
 
+pub fn spawn_lamp(commands: &mut Commands,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    render_data: &ResMut<LightRenderData>,
+    pos: Vec2,
+    params: &LampParams,
+) {
+    let [light_r, light_g, light_b] = params.color.unwrap_or(LAMP_COLOR);
+    let reach = params.reach.unwrap_or(LAMP_REACH);
+    let mut light = PointLight::new(Color::rgb(light_r, light_g, light_b), reach);
+    if let Some(flicker) = params.flicker {
+        light = light.with_flicker(flicker);
+    }
+    if let Some(period) = params.pulse {
+        light = light.with_pulse(period);
+    }
+
+    commands.spawn_bundle(MeshBundle {
+        mesh: meshes.add(render_data.base_mesh.clone().unwrap()),
+        render_pipelines: RenderPipelines::from_pipelines(vec![RenderPipeline::new(
+            render_data.pipeline_handle.clone().unwrap(),
+        )]),
+        // Underneath the guards' lights
+        transform: Transform::from_xyz(pos.x, pos.y, 0.05),
+        visible: Visible { is_transparent: true, is_visible: true },
+        ..Default::default()
+    })
+    .insert(light)
+    .insert(LightMeshData::default())
+    .insert(VisChecker{radius: reach, visible: false})
+    .insert(level::LevelEntity);
+}
+
+// Dim light that follows the player around, as far as they can see anyway
+pub fn spawn_personal_light(commands: &mut Commands,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    render_data: &ResMut<LightRenderData>,
+    reach: f32,
+) -> Entity {
+    let [light_r, light_g, light_b] = PERSONAL_LIGHT_COLOR;
+    commands.spawn_bundle(MeshBundle {
+        mesh: meshes.add(render_data.base_mesh.clone().unwrap()),
+        render_pipelines: RenderPipelines::from_pipelines(vec![RenderPipeline::new(
+            render_data.pipeline_handle.clone().unwrap(),
+        )]),
+        transform: Transform::from_xyz(0.0, 0.0, 0.05),
+        visible: Visible { is_transparent: true, is_visible: true },
+        ..Default::default()
+    })
+    .insert(PointLight::new(Color::rgb(light_r, light_g, light_b), reach).with_static_shadows())
+    .insert(PersonalLight)
+    .insert(LightMeshData::default())
+    .insert(VisChecker{radius: reach, visible: true})
+    .id()
+}
+
 fn build_mesh_for_vis_poly(poly: &geo::Polygon<f64>, mesh: &mut LightMeshData, center: Vec2, z: f32, color: Color, reach: f32) {
-    build_mesh_for_vis_poly_cone(poly, mesh, center, z, color, reach, 0.0, 4.0);
+    mesh.clear();
+    add_vis_poly_cone(poly, mesh, center, center, z, color, reach, 0.0, NO_CONE_ANGLE, NO_CONE_ANGLE, 1);
 }
 
-fn build_mesh_for_vis_poly_cone(poly: &geo::Polygon<f64>, mesh: &mut LightMeshData, center: Vec2, z: f32, color: Color, reach: f32, facing: f32, angle: f32) {
-    let mut v_pos = vec![[center.x, center.y, z]];
-    let mut v_color = vec![[color.r(), color.g(), color.b()]];
-    let mut v_lightpos = vec![[center.x, center.y, z]];
-    let mut v_lightpower = vec![reach];
-    let mut v_lightfacing = vec![facing];
-    let mut v_lightangle = vec![angle];
-    let mut indices = vec![0, 1, 2];
-    
+// Adds a fan over the visibility polygon to the mesh. The fan starts at the polygon's center, the cone and distance
+// falloff are worked out from light_pos. Samples is how many fans make up the whole light, see PENUMBRA_SAMPLES.
+fn add_vis_poly_cone(poly: &geo::Polygon<f64>, mesh: &mut LightMeshData, center: Vec2, light_pos: Vec2, z: f32, color: Color, reach: f32, facing: f32, inner_angle: f32, outer_angle: f32, samples: usize) {
+    let first_index = mesh.v_pos.len() as u32;
+    let push_vertex = |mesh: &mut LightMeshData, point: Vec2| {
+        mesh.push_vertex(point, z, color, light_pos, reach, facing, inner_angle, outer_angle, samples);
+    };
+
+    push_vertex(mesh, center);
 
     let mut point_index = 1;
     for point in poly.exterior_coords_iter()
     {
-        let vecpoint = Vec2::new(point.x as f32, point.y as f32);
-
-        v_pos.push([vecpoint.x, vecpoint.y, z]);
-        v_color.push([color.r(), color.g(), color.b()]);
-        v_lightpos.push([center.x, center.y, z]);
-        v_lightpower.push(reach);
-        v_lightfacing.push(facing);
-        v_lightangle.push(angle);
+        push_vertex(mesh, Vec2::new(point.x as f32, point.y as f32));
 
         if point_index != 1 {
-            indices.push(0);
-            indices.push(point_index);
-            indices.push(point_index - 1);
+            mesh.indices.push(first_index);
+            mesh.indices.push(first_index + point_index);
+            mesh.indices.push(first_index + point_index - 1);
         }
 
         point_index += 1;
     }
+}
 
-    mesh.v_pos = v_pos;
-    mesh.v_color = v_color;
-    mesh.v_lightpos = v_lightpos;
-    mesh.v_lightpower = v_lightpower;
-    mesh.v_lightfacing = v_lightfacing;
-    mesh.v_lightangle = v_lightangle;
-    mesh.indices = indices;
+// Adds the light reflected off a mirror to the mesh, see mirror::reflected_light. Light_pos is the mirrored light origin.
+fn add_light_strip(strip: &[(Vec2, Vec2)], mesh: &mut LightMeshData, light_pos: Vec2, z: f32, color: Color, reach: f32, facing: f32, inner_angle: f32, outer_angle: f32, samples: usize) {
+    let first_index = mesh.v_pos.len() as u32;
+    for (on_mirror, end) in strip {
+        mesh.push_vertex(*on_mirror, z, color, light_pos, reach, facing, inner_angle, outer_angle, samples);
+        mesh.push_vertex(*end, z, color, light_pos, reach, facing, inner_angle, outer_angle, samples);
+    }
+
+    for ray in 1..strip.len() as u32 {
+        let (previous, current) = (first_index + 2 * (ray - 1), first_index + 2 * ray);
+        mesh.indices.extend_from_slice(&[previous, previous + 1, current + 1, previous, current + 1, current]);
+    }
+}
+
+// The strip as a polygon, along the mirror and back along the ends of the rays
+fn strip_polygon(strip: &[(Vec2, Vec2)]) -> Polygon<f64> {
+    let ring = strip.iter().map(|(on_mirror, _end)| on_mirror)
+        .chain(strip.iter().rev().map(|(_on_mirror, end)| end))
+        .map(|point| (point.x as f64, point.y as f64))
+        .collect::<Vec<(f64, f64)>>();
+    Polygon::new(ring.into(), vec![])
 }
 
 pub fn light_mesh_applicator(
@@ -153,6 +414,8 @@ pub fn light_mesh_applicator(
                 mesh.set_attribute("light_Power", mesh_data.v_lightpower.clone());
                 mesh.set_attribute("light_Facing", mesh_data.v_lightfacing.clone());
                 mesh.set_attribute("light_Angle", mesh_data.v_lightangle.clone());
+                mesh.set_attribute("light_InnerAngle", mesh_data.v_lightinnerangle.clone());
+                mesh.set_attribute("light_Samples", mesh_data.v_lightsamples.clone());
                 mesh.set_indices(Some(bevy::render::mesh::Indices::U32(mesh_data.indices.clone())));
                 mesh_data.refresh_data = false;
 
@@ -163,16 +426,39 @@ pub fn light_mesh_applicator(
 }
 
 pub fn point_light_mesh_builder(
-    mut query: Query<(&mut PointLight, &GlobalTransform, &mut LightMeshData)>,
-    mut level_query: Query<&mut level::LevelGeo>
+    mut query: Query<(&mut PointLight, &GlobalTransform, &mut LightMeshData, &VisChecker)>,
+    level_query: Query<&level::LevelGeo>,
+    time: Res<Time>,
 ) {
-    if let Ok(mut level_geo) = level_query.single_mut() {
-        for (mut light, transform, mut mesh_data) in query.iter_mut() {
+    if let Ok(level_geo) = level_query.single() {
+        for (mut light, transform, mut mesh_data, vis_check) in query.iter_mut() {
             let center: Vec2 = transform.translation.xy();
-            let vis_polygon = level::get_visibility_polygon(&mut level_geo, center);
-            build_mesh_for_vis_poly(&vis_polygon, &mut mesh_data, center, transform.translation.z, light.color, light.reach);
-            light.mesh_built = true;
-            mesh_data.refresh_data = true;
+
+            // The shadows are kept up to date even off screen, guards use them to tell when the player is lit
+            let nearby_blockers = if light.static_shadows { vec![] } else { level_geo.temp_blocks_near(center, light.reach) };
+            let shadows_changed = light.vis_polygon.is_none() || center != light.built_center || nearby_blockers != light.nearby_blockers;
+            if shadows_changed {
+                light.vis_polygon = Some(if light.static_shadows {
+                    level::get_static_visibility_polygon(&level_geo, center)
+                } else {
+                    level::get_visibility_polygon(&level_geo, center)
+                });
+                light.built_center = center;
+                light.nearby_blockers = nearby_blockers;
+            }
+
+            let brightness = light.brightness_at(time.seconds_since_startup() as f32, center.x * 0.37 + center.y * 0.71);
+            let brightness_changed = brightness != light.brightness;
+            light.brightness = brightness;
+
+            if vis_check.visible && (shadows_changed || brightness_changed || !light.mesh_built) {
+                let color = Color::rgb(light.color.r() * brightness, light.color.g() * brightness, light.color.b() * brightness);
+                if let Some(vis_polygon) = &light.vis_polygon {
+                    build_mesh_for_vis_poly(vis_polygon, &mut mesh_data, center, transform.translation.z, color, light.reach);
+                }
+                light.mesh_built = true;
+                mesh_data.refresh_data = true;
+            }
         }
     }
 }
@@ -181,15 +467,56 @@ pub fn spotlight_mesh_builder(
     mut query: Query<(&mut SpotLight, &GlobalTransform, &Parent, &mut LightMeshData, &VisChecker)>,
     parent_query: Query<&Facing, With<Children>>,
     level_query: Query<&level::LevelGeo>,
+    mirrors: Res<Mirrors>,
     task_pool: Res<ComputeTaskPool>,
 ) {
     if let Ok(level_geo) = level_query.single() {
         query.par_for_each_mut(&task_pool, 1, |(mut light, transform, parent, mut mesh_data, vis_check)| {
             if let Ok(facing) = parent_query.get(parent.0) {
                 if vis_check.visible {
-                    let center: Vec2 = transform.translation.xy() + facing.forward() * 20.0;
-                    let vis_polygon = level::get_visibility_polygon(&level_geo, center);
-                    build_mesh_for_vis_poly_cone(&vis_polygon, &mut mesh_data, center, transform.translation.z, light.color, light.reach, facing.angle, light.angle);
+                    // The cone is measured from the guard itself, same as its sight, but the shadows are cast from a
+                    // little in front so the guard's own light blocker doesn't swallow the light
+                    let origin = transform.translation.xy();
+                    let center: Vec2 = origin + facing.forward() * 20.0;
+                    let side = Vec2::new(-facing.forward().y, facing.forward().x);
+                    let samples = light.samples();
+                    let z = transform.translation.z;
+
+                    // Only mirrors the light can reach get rays cast at them, and only against the walls it can reach
+                    let lit_mirrors = mirrors.0.iter()
+                        .filter(|mirror| mirror.distance_to(center) < light.reach + light.softness)
+                        .collect::<Vec<&Mirror>>();
+                    let blockers = if lit_mirrors.is_empty() { vec![] } else { level_geo.blocker_segments_near(center, light.reach + light.softness) };
+
+                    mesh_data.clear();
+                    light.lit_areas.clear();
+                    for sample in 0..samples {
+                        let offset = if samples == 1 { 0.0 } else { (sample as f32 / (samples - 1) as f32 - 0.5) * light.softness };
+                        let sample_center = center + side * offset;
+                        let light_pos = origin + side * offset;
+                        let vis_polygon = level::get_visibility_polygon(&level_geo, sample_center);
+                        add_vis_poly_cone(&vis_polygon, &mut mesh_data, sample_center, light_pos, z,
+                            light.color, light.reach, facing.angle, light.inner_angle, light.outer_angle, samples);
+                        light.lit_areas.push(LitArea { polygon: vis_polygon, cast_from: sample_center, light_pos, facing: facing.angle });
+
+                        // Light bouncing off mirrors is lit as if it came from the light's reflection, so the cone and
+                        // falloff carry on from where they were at the mirror
+                        for mirror in lit_mirrors.iter() {
+                            if let Some(strip) = reflected_light(sample_center, light.reach, mirror, &blockers, REFLECTION_RAYS) {
+                                let mirrored_light_pos = mirror.reflect_point(light_pos);
+                                let mirrored_forward = mirror.reflect_direction(facing.forward());
+                                let mirrored_facing = mirrored_forward.y.atan2(mirrored_forward.x);
+                                add_light_strip(&strip, &mut mesh_data, mirrored_light_pos, z,
+                                    light.color, light.reach, mirrored_facing, light.inner_angle, light.outer_angle, samples);
+                                light.lit_areas.push(LitArea {
+                                    polygon: strip_polygon(&strip),
+                                    cast_from: mirror.reflect_point(sample_center),
+                                    light_pos: mirrored_light_pos,
+                                    facing: mirrored_facing,
+                                });
+                            }
+                        }
+                    }
                     light.mesh_built = true;
                     mesh_data.refresh_data = true;
                 }
@@ -209,10 +536,94 @@ pub fn test_spin_system(
 }
 
 
+// Samples every light at the player's position. Their own light doesn't count, it only helps them see.
+pub fn light_exposure_system(
+    mut exposure: ResMut<LightExposure>,
+    ambient: Res<AmbientLight>,
+    player_query: Query<&Transform, With<crate::player::PlayerMovement>>,
+    point_light_query: Query<(&PointLight, &GlobalTransform), Without<PersonalLight>>,
+    spotlight_query: Query<&SpotLight>,
+) {
+    if let Ok(player_transform) = player_query.single() {
+        let player_position = player_transform.translation.xy();
+        let from_lamps: f32 = point_light_query.iter()
+            .map(|(light, transform)| light.light_at(transform.translation.xy(), player_position))
+            .sum();
+        let from_spotlights: f32 = spotlight_query.iter()
+            .map(|light| light.light_at(player_position))
+            .sum();
+
+        exposure.from_lamps = from_lamps;
+        exposure.level = (ambient.level + from_lamps + from_spotlights).min(1.0);
+    }
+}
+
+// Keeps the ambient quad at the current level's ambient light
+pub fn ambient_light_system(
+    ambient: Res<AmbientLight>,
+    state: Res<State<GameState>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    query: Query<&Handle<Mesh>, With<AmbientQuad>>,
+) {
+    if !ambient.is_changed() && !state.is_changed() { return; }
+
+    // Everything is lit while editing so the whole layout can be seen
+    let level = if *state.current() == GameState::Editor { 1.0 } else { ambient.level.clamp(0.0, 1.0) };
+    for mesh_handle in query.iter() {
+        if let Some(mesh) = meshes.get_mut(mesh_handle) {
+            mesh.set_attribute("darkness_Alpha", vec![level; 4]);
+        }
+    }
+}
+
+fn blend(src_factor: BlendFactor, dst_factor: BlendFactor) -> BlendState {
+    BlendState { src_factor, dst_factor, operation: BlendOperation::Add }
+}
+
+// Pipeline for one of the screen covering quads of the darkness pass
+fn darkness_pipeline(shaders: &mut ResMut<Assets<Shader>>, color_blend: BlendState, alpha_blend: BlendState) -> PipelineDescriptor {
+    let mut pipeline = PipelineDescriptor::default_config(ShaderStages {
+        vertex: shaders.add(Shader::from_glsl(ShaderStage::Vertex, DARKNESS_VERTEX_SHADER)),
+        fragment: Some(shaders.add(Shader::from_glsl(ShaderStage::Fragment, DARKNESS_FRAGMENT_SHADER))),
+    });
+
+    for color_state in &mut pipeline.color_target_states {
+        color_state.color_blend = color_blend.clone();
+        color_state.alpha_blend = alpha_blend.clone();
+    }
+    // Drawn in screen space, so the depth from the scene means nothing to it
+    if let Some(depth_stencil) = &mut pipeline.depth_stencil {
+        depth_stencil.depth_write_enabled = false;
+        depth_stencil.depth_compare = CompareFunction::Always;
+    }
+    return pipeline;
+}
+
+fn spawn_darkness_quad(commands: &mut Commands, meshes: &mut ResMut<Assets<Mesh>>, pipeline: Handle<PipelineDescriptor>, alpha: f32, z: f32) -> Entity {
+    let mut mesh = Mesh::new(bevy::render::pipeline::PrimitiveTopology::TriangleList);
+    mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, vec![[-1.0, -1.0, 0.0], [1.0, -1.0, 0.0], [1.0, 1.0, 0.0], [-1.0, 1.0, 0.0]]);
+    mesh.set_attribute("darkness_Alpha", vec![alpha; 4]);
+    mesh.set_indices(Some(bevy::render::mesh::Indices::U32(vec![0, 1, 2, 0, 2, 3])));
+
+    commands.spawn_bundle(MeshBundle {
+        mesh: meshes.add(mesh),
+        render_pipelines: RenderPipelines::from_pipelines(vec![RenderPipeline::new(pipeline)]),
+        // Only the z matters, it's what orders the quads among everything else drawn
+        transform: Transform::from_xyz(0.0, 0.0, z),
+        visible: Visible { is_transparent: true, is_visible: true },
+        ..Default::default()
+    })
+    .insert(crate::Preserve)
+    .id()
+}
+
 pub fn light_setup_system(
+    mut commands: Commands,
     mut pipelines: ResMut<Assets<PipelineDescriptor>>,
     mut render_data: ResMut<LightRenderData>,
     mut shaders: ResMut<Assets<Shader>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    ambient: Res<AmbientLight>,
 ) {
     
     let mut pipeline = PipelineDescriptor::default_config(ShaderStages {
@@ -220,10 +631,28 @@ pub fn light_setup_system(
         fragment: Some(shaders.add(Shader::from_glsl(ShaderStage::Fragment, FRAGMENT_SHADER))),
     });
 
+    // Lights add up in the alpha channel and tint the scene underneath a little
     for color_state in &mut pipeline.color_target_states {
-        color_state.alpha_blend.operation = BlendOperation::Add;
-        color_state.color_blend.operation = BlendOperation::Add;
+        color_state.color_blend = blend(BlendFactor::SrcAlpha, BlendFactor::One);
+        color_state.alpha_blend = blend(BlendFactor::One, BlendFactor::One);
     }
+    // Reflections wind the other way round from the light that made them
+    pipeline.primitive.cull_mode = CullMode::None;
+
+    // Leave the scene alone and start the light buffer off at the ambient level
+    let ambient_pipeline = pipelines.add(darkness_pipeline(&mut shaders,
+        blend(BlendFactor::Zero, BlendFactor::One),
+        blend(BlendFactor::One, BlendFactor::Zero),
+    ));
+    let ambient_quad = spawn_darkness_quad(&mut commands, &mut meshes, ambient_pipeline, ambient.level, AMBIENT_QUAD_Z);
+    commands.entity(ambient_quad).insert(AmbientQuad);
+
+    // Multiply the scene by the light buffer, then put the alpha back
+    let composite_pipeline = pipelines.add(darkness_pipeline(&mut shaders,
+        blend(BlendFactor::Zero, BlendFactor::DstAlpha),
+        blend(BlendFactor::One, BlendFactor::Zero),
+    ));
+    spawn_darkness_quad(&mut commands, &mut meshes, composite_pipeline, 1.0, COMPOSITE_QUAD_Z);
 
     render_data.pipeline_handle = Some(pipelines.add(pipeline));
     let mut mesh = Mesh::new(bevy::render::pipeline::PrimitiveTopology::TriangleList);
@@ -235,6 +664,8 @@ pub fn light_setup_system(
     let v_lightpower = vec![1.0, 1.0, 1.0];
     let v_lightfacing = vec![0.0, 0.0, 0.0];
     let v_lightangle = vec![1.0, 1.0, 1.0];
+    let v_lightinnerangle = vec![1.0, 1.0, 1.0];
+    let v_lightsamples = vec![1.0, 1.0, 1.0];
     let indices = vec![0, 1, 2];
 
     mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, v_pos);
@@ -243,6 +674,8 @@ pub fn light_setup_system(
     mesh.set_attribute("light_Power", v_lightpower);
     mesh.set_attribute("light_Facing", v_lightfacing);
     mesh.set_attribute("light_Angle", v_lightangle);
+    mesh.set_attribute("light_InnerAngle", v_lightinnerangle);
+    mesh.set_attribute("light_Samples", v_lightsamples);
     mesh.set_indices(Some(bevy::render::mesh::Indices::U32(indices)));
 
     render_data.base_mesh = Some(mesh);
@@ -262,6 +695,10 @@ layout(location = 5) in float light_Facing;
 layout(location = 5) out float l_Facing;
 layout(location = 6) in float light_Angle;
 layout(location = 6) out float l_Angle;
+layout(location = 7) in float light_InnerAngle;
+layout(location = 7) out float l_InnerAngle;
+layout(location = 8) in float light_Samples;
+layout(location = 8) out float l_Samples;
 layout(set = 0, binding = 0) uniform CameraViewProj {
     mat4 ViewProj;
 };
@@ -271,6 +708,8 @@ void main() {
     l_power = light_Power;
     l_Facing = light_Facing;
     l_Angle = light_Angle;
+    l_InnerAngle = light_InnerAngle;
+    l_Samples = light_Samples;
     gl_Position = ViewProj * vec4(Vertex_Position, 1.0);
     position = Vertex_Position;
 }
@@ -284,16 +723,47 @@ layout(location = 3) in vec3 position;
 layout(location = 4) in float l_power;
 layout(location = 5) in float l_Facing;
 layout(location = 6) in float l_Angle;
+layout(location = 7) in float l_InnerAngle;
+layout(location = 8) in float l_Samples;
 layout(location = 0) out vec4 o_Target;
+// How much of its color a light adds to what it lights, on top of making it brighter
+const float LIGHT_TINT = 0.4;
 void main() {
     vec3 to_source = l_Position - position;
     float distance = length(to_source);
     to_source = to_source / distance;
     vec3 facing = vec3(-cos(l_Facing), -sin(l_Facing), to_source.z);
     float angle = acos(dot(to_source, facing));
-    float angle_falloff = l_Angle <= angle ? 0 : 1;//clamp(1 - (angle / l_Angle), 0.0, 1.0);
+    // Smooth from full at the inner angle to nothing at the outer, lights without a cone have both the same
+    float angle_falloff = l_InnerAngle < l_Angle ? 1.0 - smoothstep(l_InnerAngle, l_Angle, angle) : step(angle, l_Angle);
     float light_power = clamp(1 - (distance / l_power), 0.0, 1.0);
     light_power = pow(light_power, 3) * angle_falloff;
-    o_Target = vec4(l_Color.x, l_Color.y, l_Color.z, light_power);
+    // A light is as strong as its brightest color channel, so dim colors light less
+    float strength = max(l_Color.x, max(l_Color.y, l_Color.z));
+    // Each of the overlapping penumbra samples adds its share, so where they all overlap it adds up to the full power
+    float sample_power = light_power * strength / l_Samples;
+    o_Target = vec4(l_Color * LIGHT_TINT, sample_power);
+}
+";
+
+pub const DARKNESS_VERTEX_SHADER: &str = r"
+#version 450
+layout(location = 0) in vec3 Vertex_Position;
+layout(location = 1) in float darkness_Alpha;
+layout(location = 1) out float v_Alpha;
+void main() {
+    v_Alpha = darkness_Alpha;
+    // Already in clip space so the quad always covers the whole screen
+    gl_Position = vec4(Vertex_Position.xy, 0.0, 1.0);
+}
+";
+
+// Only the alpha matters, the blend states of each darkness pipeline decide what's done with it
+pub const DARKNESS_FRAGMENT_SHADER: &str = r"
+#version 450
+layout(location = 1) in float v_Alpha;
+layout(location = 0) out vec4 o_Target;
+void main() {
+    o_Target = vec4(0.0, 0.0, 0.0, v_Alpha);
 }
 ";
\ No newline at end of file
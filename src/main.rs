@@ -1,6 +1,7 @@
 #![windows_subsystem = "windows"]
 use bevy::{
     prelude::*, 
+    asset::AssetServerSettings,
     window::WindowMode,
     diagnostic::{Diagnostics, FrameTimeDiagnosticsPlugin},
 };
@@ -9,18 +10,34 @@ use nalgebra::Vector2;
 
 mod player;
 mod level;
+mod level_gen;
+mod nav;
+mod steering;
+mod smoke;
+mod exposure;
+mod mirror;
+mod archetype;
+mod search;
+mod editor;
+mod level_select;
 mod particles;
 mod ai;
+mod guard_state;
 mod lighting;
 mod gamestate;
 mod pickup;
 mod visibility;
 
+use smoke_and_mirrors::level_data;
 use gamestate::{GameState, Score};
 
 pub struct MainCam;
 
 fn main() {
+    // --level <name> skips the title screen and goes straight into that level
+    let level_arg = std::env::args().skip_while(|arg| arg != "--level").nth(1);
+    let start_state = if level_arg.is_some() { GameState::Playing } else { GameState::Startup };
+
     App::build()
         .insert_resource(WindowDescriptor {
             title: "Smoke and Mirrors".to_string(),
@@ -32,12 +49,17 @@ fn main() {
             ..Default::default()
         })
         .insert_resource(ClearColor(Color::rgb(0.1, 0.1, 0.1)))
-        .insert_resource(gamestate::Score{value: 0, max: 0})
-        .insert_resource(gamestate::CurrentLevel{name: "game".to_string()})
+        .insert_resource(gamestate::Score{value: 0, max: 0, time: 0.0})
+        .insert_resource(gamestate::CurrentLevel::new(level_arg.as_deref().unwrap_or("game")))
         .insert_resource(gamestate::PerfDebug{spotlight_updates: 0})
+        // Level files get reloaded when they're saved
+        .insert_resource(AssetServerSettings {
+            asset_folder: "assets".to_string(),
+            watch_for_changes: true,
+        })
         .add_plugin(FrameTimeDiagnosticsPlugin::default())
         .add_plugins(DefaultPlugins)
-        .add_state(GameState::Startup)
+        .add_state(start_state)
         .add_system(visibility::vis_checking_system.system())
         .add_system(visibility::vis_debug_system.system())
         .add_plugin(RapierPhysicsPlugin::<NoUserData>::default())
@@ -46,6 +68,8 @@ fn main() {
         .add_plugin(ai::AiPlugin)
         .add_plugin(lighting::LightingPlugin)
         .add_plugin(particles::ParticlePlugin)
+        .add_plugin(editor::EditorPlugin)
+        .add_plugin(level_select::LevelSelectPlugin)
         .add_startup_system(all_setup.system().label("physics"))
         .add_system_set(SystemSet::on_enter(GameState::Playing)
             .with_system(level::setup_environment.system())
@@ -53,6 +77,8 @@ fn main() {
         .add_system_set(SystemSet::on_exit(GameState::Startup).with_system(teardown.system()))
         .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(teardown.system()))
         .add_system_set(SystemSet::on_exit(GameState::GameOver).with_system(teardown.system()))
+        .add_system_set(SystemSet::on_exit(GameState::LevelComplete).with_system(teardown.system()))
+        .add_system_set(SystemSet::on_exit(GameState::Editor).with_system(teardown.system()))
         .add_system_set(
             SystemSet::on_enter(GameState::Startup).with_system(startup_setup.system()),
         )
@@ -60,7 +86,12 @@ fn main() {
             SystemSet::on_enter(GameState::Playing).with_system(setup_playing.system()),
         )
         .add_system_set(
-            SystemSet::on_update(GameState::Startup).with_system(gamestate::startgame_keyboard.system()),
+            SystemSet::on_update(GameState::Playing)
+                .with_system(gamestate::level_timer_system.system())
+                .with_system(visibility_gem_system.system()),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameState::Startup).with_system(gamestate::title_keyboard.system()),
         )
         .add_system_set(
             SystemSet::on_enter(GameState::GameOver).with_system(gameover_setup.system()),
@@ -68,6 +99,12 @@ fn main() {
         .add_system_set(
             SystemSet::on_update(GameState::GameOver).with_system(gamestate::startgame_keyboard.system()),
         )
+        .add_system_set(
+            SystemSet::on_enter(GameState::LevelComplete).with_system(level_complete_setup.system()),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameState::LevelComplete).with_system(gamestate::level_complete_keyboard.system()),
+        )
         .add_system(screen_text.system())
         // END
         .run();
@@ -97,7 +134,7 @@ fn startup_setup(
                         },
                     },
                     TextSection {
-                        value: "\n[Space] to start game\n[Esc] to quit".to_string(),
+                        value: "\n[Up/Down] to choose a level\n[Space] to start game\n[G] for endless generated levels\n[E] to edit the level\n[Esc] to quit".to_string(),
                         style: TextStyle {
                             font: asset_server.load("fonts/Roboto-Regular.ttf"),
                             font_size: 40.0,
@@ -113,7 +150,7 @@ fn startup_setup(
                         },
                     },
                     TextSection {
-                        value: "\n\nControls:\n[WASD] to move\n[Space] to drop smoke bomb".to_string(),
+                        value: "\n\nControls:\n[WASD] to move\n[Shift] to sneak quietly\n[Space] to drop smoke bomb\n[F3] to show guard states\n[F5] to save a generated level".to_string(),
                         style: TextStyle {
                             font: asset_server.load("fonts/Roboto-Regular.ttf"),
                             font_size: 40.0,
@@ -137,7 +174,7 @@ fn startup_setup(
 }
 
 struct DiagText;
-struct Preserve;
+pub struct Preserve;
 fn screen_text(
     diagnostics: Res<Diagnostics>,
     score: Res<Score>,
@@ -212,7 +249,73 @@ fn gameover_setup(
                 ..Default::default()
             });
 
-        score.value = 0;
+        score.reset();
+}
+
+fn level_complete_setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    score: Res<Score>,
+    current_level: Res<gamestate::CurrentLevel>,
+) {
+    let time_text = match current_level.par_time {
+        Some(par_time) => format!("\nTime: {:.1}s (Par {:.0}s)", score.time, par_time),
+        None => format!("\nTime: {:.1}s", score.time),
+    };
+
+    let (title, prompt) = if current_level.editing.is_some() {
+        ("Level Complete!", "\n[Space] to return to the editor\n[Esc] to quit")
+    }
+    else if current_level.is_last_level() {
+        ("Campaign Complete!", "\n[Space] to return to title\n[Esc] to quit")
+    }
+    else {
+        ("Level Complete!", "\n[Space] to continue\n[Esc] to quit")
+    };
+    let heading = if current_level.title.is_empty() { title.to_string() } else { format!("{}\n{}", current_level.title, title) };
+
+    commands
+        .spawn_bundle(TextBundle {
+                text: Text {
+                    sections: vec![
+                        TextSection {
+                            value: heading,
+                            style: TextStyle {
+                                font: asset_server.load("fonts/Roboto-Regular.ttf"),
+                                font_size: 80.0,
+                                color: Color::rgb(0.6, 0.6, 1.0)
+                            },
+                        },
+                        TextSection {
+                            value: format!("\nCards Found: {}/{}", score.value, score.max) + &time_text,
+                            style: TextStyle {
+                                font: asset_server.load("fonts/Roboto-Regular.ttf"),
+                                font_size: 60.0,
+                                color: Color::rgb(0.4, 0.4, 1.0)
+                            },
+                        },
+                        TextSection {
+                            value: prompt.to_string(),
+                            style: TextStyle {
+                                font: asset_server.load("fonts/Roboto-Regular.ttf"),
+                                font_size: 40.0,
+                                color: Color::rgb(0.4, 0.4, 1.0)
+                            },
+                        },
+                    ],
+                    ..Default::default()
+                },
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    position: Rect {
+                        top: Val::Px(5.0),
+                        left: Val::Px(5.0),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            });
 }
 
 fn all_setup(
@@ -234,6 +337,8 @@ fn all_setup(
 fn setup_playing(
     mut commands: Commands, 
     asset_server: Res<AssetServer>, 
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    exposure: Res<exposure::LightExposure>,
 ) {
     
     commands.spawn_bundle(TextBundle {
@@ -318,6 +423,38 @@ fn setup_playing(
         ..Default::default()
     })
     .insert(DiagText);
+
+    commands.spawn_bundle(NodeBundle {
+        style: Style {
+            size: Size::new(Val::Px(32.0), Val::Px(32.0)),
+            position_type: PositionType::Absolute,
+            position: Rect {
+                top: Val::Px(10.0),
+                right: Val::Px(10.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        material: materials.add(exposure.gem_color().into()),
+        ..Default::default()
+    })
+    .insert(VisibilityGem);
+}
+
+// Shows how lit the player is, the brighter it glows the easier they are to spot
+struct VisibilityGem;
+fn visibility_gem_system(
+    exposure: Res<exposure::LightExposure>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    query: Query<&Handle<ColorMaterial>, With<VisibilityGem>>,
+) {
+    if !exposure.is_changed() { return; }
+
+    for material_handle in query.iter() {
+        if let Some(material) = materials.get_mut(material_handle) {
+            material.color = exposure.gem_color();
+        }
+    }
 }
 
 fn teardown(mut commands: Commands, entities: Query<Entity, Without<Preserve>>) {
@@ -0,0 +1,218 @@
+use bevy::prelude::*;
+
+use crate::level_data::{GridPos, LevelTiles, TileValue};
+
+// Rays cast across each mirror when working out what it lights
+pub const REFLECTION_RAYS: usize = 12;
+// Hits closer than this to where a ray starts are the surface it's leaving
+const SURFACE_EPSILON: f32 = 1.0;
+
+// The reflective face of a run of mirror tiles
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mirror {
+    pub start: Vec2,
+    pub end: Vec2,
+    // Points out of the mirror into the open
+    pub normal: Vec2,
+}
+
+// Every mirror face in the current level, set when the level is built
+#[derive(Default)]
+pub struct Mirrors(pub Vec<Mirror>);
+
+impl Mirror {
+    pub fn new(start: Vec2, end: Vec2, normal: Vec2) -> Mirror {
+        Mirror { start, end, normal }
+    }
+
+    pub fn reflect_point(&self, point: Vec2) -> Vec2 {
+        point - 2.0 * (point - self.start).dot(self.normal) * self.normal
+    }
+
+    pub fn reflect_direction(&self, direction: Vec2) -> Vec2 {
+        direction - 2.0 * direction.dot(self.normal) * self.normal
+    }
+
+    // Whether a point is on the reflective side
+    pub fn faces(&self, point: Vec2) -> bool {
+        (point - self.start).dot(self.normal) > 0.0
+    }
+
+    // Where the line from a point in front of the mirror to one behind it crosses the mirror, None if it misses.
+    // Looking at the reflection of something is looking along this line.
+    pub fn hit(&self, from: Vec2, to: Vec2) -> Option<Vec2> {
+        if !self.faces(from) || self.faces(to) {
+            return None;
+        }
+        return segment_intersection(from, to, self.start, self.end).map(|t| from.lerp(to, t));
+    }
+
+    pub fn distance_to(&self, point: Vec2) -> f32 {
+        let along = self.end - self.start;
+        let t = ((point - self.start).dot(along) / along.length_squared()).clamp(0.0, 1.0);
+        return point.distance(self.start + along * t);
+    }
+}
+
+// How far along the line from a to b it crosses the line from c to d, as a fraction of a to b
+fn segment_intersection(a: Vec2, b: Vec2, c: Vec2, d: Vec2) -> Option<f32> {
+    let ab = b - a;
+    let cd = d - c;
+    let denominator = ab.perp_dot(cd);
+    if denominator.abs() < f32::EPSILON {
+        return None;
+    }
+
+    let t = (c - a).perp_dot(cd) / denominator;
+    let u = (c - a).perp_dot(ab) / denominator;
+    if (0.0..=1.0).contains(&t) && (0.0..=1.0).contains(&u) { Some(t) } else { None }
+}
+
+// How far a ray gets before running into any of the blocker edges, up to max_distance. Direction has to be normalized.
+pub fn ray_distance(origin: Vec2, direction: Vec2, max_distance: f32, blockers: &[(Vec2, Vec2)]) -> f32 {
+    let end = origin + direction * max_distance;
+    blockers.iter()
+        .filter_map(|(start, finish)| segment_intersection(origin, end, *start, *finish))
+        .map(|t| t * max_distance)
+        .filter(|distance| *distance > SURFACE_EPSILON)
+        .fold(max_distance, f32::min)
+}
+
+// Area lit by light from source bouncing off the mirror, as pairs of where each ray meets the mirror and where it ends up.
+// The rays come from the mirrored light origin through the mirror, so it only ever covers what can be seen in it.
+// Rays that can't get from the source to the mirror end where they start. None if the mirror faces away or is out of reach.
+pub fn reflected_light(source: Vec2, reach: f32, mirror: &Mirror, blockers: &[(Vec2, Vec2)], rays: usize) -> Option<Vec<(Vec2, Vec2)>> {
+    if !mirror.faces(source) || mirror.distance_to(source) >= reach || rays < 2 {
+        return None;
+    }
+
+    let mirrored_source = mirror.reflect_point(source);
+    let strip = (0..rays).map(|ray| {
+        let on_mirror = mirror.start.lerp(mirror.end, ray as f32 / (rays - 1) as f32);
+        let travelled = source.distance(on_mirror);
+        if travelled >= reach || ray_distance(source, (on_mirror - source) / travelled, travelled, blockers) < travelled - SURFACE_EPSILON {
+            return (on_mirror, on_mirror);
+        }
+
+        let direction = (on_mirror - mirrored_source).normalize();
+        (on_mirror, on_mirror + direction * ray_distance(on_mirror, direction, reach - travelled, blockers))
+    }).collect();
+
+    return Some(strip);
+}
+
+// Faces of mirror tiles that open onto floor, each straight run joined into one mirror
+pub fn level_mirrors(level: &LevelTiles) -> Vec<Mirror> {
+    let mut mirrors = Vec::<Mirror>::new();
+    let half = 0.5 * level.tile_size;
+    let open_face = |x: i32, y: i32, dx: i32, dy: i32| {
+        level.get_tile(&GridPos{x, y}) == TileValue::Mirror && !level.get_tile(&GridPos{x: x + dx, y: y + dy}).is_solid()
+    };
+
+    for (dx, dy) in [(1, 0), (-1, 0), (0, 1), (0, -1)].iter() {
+        let normal = Vec2::new(*dx as f32, *dy as f32);
+        let along = Vec2::new(normal.y.abs(), normal.x.abs());
+        // Faces pointing along x are in columns and run up them, the others are in rows and run along them
+        let (lines, length) = if *dx != 0 { (level.width as i32, level.height as i32) } else { (level.height as i32, level.width as i32) };
+        let tile_at = |line: i32, step: i32| if *dx != 0 { GridPos{x: line, y: step} } else { GridPos{x: step, y: line} };
+
+        for line in 0..lines {
+            let mut run_start: Option<i32> = None;
+            for step in 0..=length {
+                let tile = tile_at(line, step);
+                let open = step < length && open_face(tile.x, tile.y, *dx, *dy);
+                match (open, run_start) {
+                    (true, None) => run_start = Some(step),
+                    (false, Some(start)) => {
+                        let first = level.grid_to_world(tile_at(line, start)) + normal * half - along * half;
+                        let last = level.grid_to_world(tile_at(line, step - 1)) + normal * half + along * half;
+                        mirrors.push(Mirror::new(first, last, normal));
+                        run_start = None;
+                    },
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    return mirrors;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Mirror along the x axis from -100 to 100, reflecting upwards
+    fn floor_mirror() -> Mirror {
+        Mirror::new(Vec2::new(-100.0, 0.0), Vec2::new(100.0, 0.0), Vec2::new(0.0, 1.0))
+    }
+
+    #[test]
+    fn test_reflection() {
+        let mirror = floor_mirror();
+        assert_eq!(mirror.reflect_point(Vec2::new(30.0, 50.0)), Vec2::new(30.0, -50.0));
+        assert_eq!(mirror.reflect_direction(Vec2::new(1.0, -1.0)), Vec2::new(1.0, 1.0));
+        assert!(mirror.faces(Vec2::new(0.0, 10.0)));
+        assert!(!mirror.faces(Vec2::new(0.0, -10.0)));
+
+        // Looking at the reflection of something goes via the mirror halfway between
+        let guard = Vec2::new(-50.0, 50.0);
+        let player = Vec2::new(50.0, 50.0);
+        assert_eq!(mirror.hit(guard, mirror.reflect_point(player)), Some(Vec2::new(0.0, 0.0)));
+        assert_eq!(mirror.hit(guard, mirror.reflect_point(Vec2::new(500.0, 50.0))), None, "Reflection is past the end of the mirror");
+        assert_eq!(mirror.hit(Vec2::new(0.0, -50.0), Vec2::new(0.0, -100.0)), None, "Behind the mirror");
+    }
+
+    #[test]
+    fn test_reflected_light_is_clipped_to_the_mirror() {
+        let mirror = floor_mirror();
+        let strip = reflected_light(Vec2::new(0.0, 100.0), 1000.0, &mirror, &[], 5).unwrap();
+        assert_eq!(strip.len(), 5);
+        assert_eq!(strip[0].0, mirror.start);
+        assert_eq!(strip[4].0, mirror.end);
+
+        // Rays fan out from the mirrored source, so the lit area stays within the lines through the mirror's ends
+        let mirrored_source = Vec2::new(0.0, -100.0);
+        for (on_mirror, end) in strip.iter() {
+            assert!(end.y > 0.0);
+            let direction = (*end - *on_mirror).normalize();
+            assert!((direction - (*on_mirror - mirrored_source).normalize()).length() < 0.001);
+            assert!((mirrored_source.distance(*end) - 1000.0).abs() < 0.1, "Light goes as far as its reach in total");
+        }
+
+        assert!(reflected_light(Vec2::new(0.0, -100.0), 1000.0, &mirror, &[], 5).is_none(), "Light behind the mirror");
+        assert!(reflected_light(Vec2::new(0.0, 100.0), 50.0, &mirror, &[], 5).is_none(), "Out of reach");
+    }
+
+    #[test]
+    fn test_reflected_light_is_blocked() {
+        let mirror = floor_mirror();
+        // A wall between the light and the right half of the mirror, and a ceiling for the reflection to hit
+        let blockers = [
+            (Vec2::new(10.0, 20.0), Vec2::new(200.0, 20.0)),
+            (Vec2::new(-500.0, 150.0), Vec2::new(500.0, 150.0)),
+        ];
+        let strip = reflected_light(Vec2::new(0.0, 100.0), 1000.0, &mirror, &blockers, 5).unwrap();
+        assert_eq!(strip[4].0, strip[4].1, "In shadow so nothing is reflected");
+        assert!((strip[2].1 - Vec2::new(0.0, 150.0)).length() < 0.001, "Straight back up to the ceiling");
+        assert!((strip[0].1 - Vec2::new(-250.0, 150.0)).length() < 0.001, "Off to the side and up to the ceiling");
+    }
+
+    #[test]
+    fn test_level_mirrors() {
+        let level = LevelTiles::parse("next\n#####\n#V  #\n#%% #\n#####\n").unwrap();
+        let mirrors = level_mirrors(&level);
+
+        // The two mirror tiles share one face onto the floor above them, and the right one has a face onto the floor beside it
+        assert_eq!(mirrors.len(), 2);
+        let top = mirrors.iter().find(|mirror| mirror.normal == Vec2::new(0.0, -1.0)).unwrap();
+        assert_eq!(top.start.distance(top.end), 2.0 * level.tile_size);
+        let side = mirrors.iter().find(|mirror| mirror.normal == Vec2::new(1.0, 0.0)).unwrap();
+        assert_eq!(side.start.distance(side.end), level.tile_size);
+
+        // Faces sit on the edge between the mirror and the floor
+        let floor = level.grid_to_world(GridPos{x: 3, y: 2});
+        assert_eq!(side.start.x, floor.x - 0.5 * level.tile_size);
+        assert!(!level.is_walkable_at(level.grid_to_world(GridPos{x: 1, y: 2})));
+    }
+}
@@ -0,0 +1,342 @@
+use bevy::prelude::*;
+use pathfinding::prelude::astar;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::level_data::{grid_successors, GridPos, GridSpace, LevelTiles};
+
+const UNREACHABLE: u32 = u32::MAX;
+// How far along the chase flow field to look for a tile that can be walked to in a straight line
+const FLOW_LOOKAHEAD: usize = 8;
+
+// Which tiles of the current level can be walked on, worked out once when the level is built
+// so pathfinding doesn't have to look at the tile data every step
+#[derive(Default)]
+pub struct NavGrid {
+    width: usize,
+    height: usize,
+    space: GridSpace,
+    walkable: Vec<bool>,
+}
+
+impl NavGrid {
+    pub fn new(level: &LevelTiles) -> NavGrid {
+        NavGrid {
+            width: level.width,
+            height: level.height,
+            space: level.grid_space(),
+            walkable: level.tiles.iter().map(|tile| !tile.is_solid()).collect(),
+        }
+    }
+
+    pub fn world_to_grid(&self, pos: Vec2) -> GridPos {
+        self.space.world_to_grid(pos)
+    }
+
+    pub fn grid_to_world(&self, pos: &GridPos) -> Vec2 {
+        self.space.grid_to_world(pos)
+    }
+
+    fn index(&self, pos: &GridPos) -> Option<usize> {
+        if pos.x < 0 || pos.y < 0 || pos.x as usize >= self.width || pos.y as usize >= self.height {
+            return None;
+        }
+        return Some(pos.x as usize + pos.y as usize * self.width);
+    }
+
+    pub fn is_walkable(&self, pos: &GridPos) -> bool {
+        self.index(pos).map_or(false, |index| self.walkable[index])
+    }
+
+    // Same moves as LevelTiles pathfinding
+    fn successors(&self, pos: &GridPos) -> Vec<(GridPos, u32)> {
+        grid_successors(pos, |next| self.is_walkable(next))
+    }
+
+    fn find_cells(&self, start: &GridPos, goal: &GridPos) -> Option<(Vec<GridPos>, u32)> {
+        astar(
+            start,
+            |pos| self.successors(pos),
+            |pos| pos.distance(goal) / 3,
+            |pos| pos == goal
+        )
+    }
+
+    // A* between two points, used for one-off trips like patrols and searches
+    pub fn find_path(&self, from: Vec2, to: Vec2) -> Option<Vec<Vec2>> {
+        let (cells, _cost) = self.find_cells(&self.world_to_grid(from), &self.world_to_grid(to))?;
+        return Some(cells.iter().map(|pos| self.grid_to_world(pos)).collect());
+    }
+
+    // Whether something of the given radius can move in a straight line between two points without clipping a wall,
+    // checked along both of its edges and the middle every quarter tile
+    pub fn clear_line(&self, from: Vec2, to: Vec2, radius: f32) -> bool {
+        let direction = to - from;
+        let side = direction.try_normalize().map_or(Vec2::default(), |direction| Vec2::new(-direction.y, direction.x) * radius);
+        let steps = (direction.length() / (0.25 * self.space.tile_size())).ceil() as i32;
+
+        for step in 0..=steps {
+            let t = if steps == 0 { 0.0 } else { step as f32 / steps as f32 };
+            let point = from + direction * t;
+            for offset in [Vec2::default(), side, -side].iter() {
+                if !self.is_walkable(&self.world_to_grid(point + *offset)) {
+                    return false;
+                }
+            }
+        }
+        return true;
+    }
+
+    // String pulls a grid path so it only turns at corners, each point is the furthest one along the path that
+    // can be walked to in a straight line from the last. The start position itself isn't included.
+    pub fn smooth_path(&self, from: Vec2, path: &[Vec2], radius: f32) -> Vec<Vec2> {
+        let mut smoothed = Vec::<Vec2>::new();
+        let mut anchor = from;
+        let mut index = 0;
+
+        while index < path.len() {
+            let mut furthest = index;
+            while furthest + 1 < path.len() && self.clear_line(anchor, path[furthest + 1], radius) {
+                furthest += 1;
+            }
+            anchor = path[furthest];
+            smoothed.push(anchor);
+            index = furthest + 1;
+        }
+        return smoothed;
+    }
+
+    // Cost of getting to the goal from every tile, so any number of guards can head there without pathfinding
+    pub fn flow_field(&self, goal: &GridPos) -> FlowField {
+        let mut costs = vec![UNREACHABLE; self.walkable.len()];
+        let mut open = BinaryHeap::new();
+
+        if let Some(index) = self.index(goal).filter(|index| self.walkable[*index]) {
+            costs[index] = 0;
+            open.push(Reverse((0, goal.x, goal.y)));
+        }
+
+        while let Some(Reverse((cost, x, y))) = open.pop() {
+            let pos = GridPos{x, y};
+            if cost > costs[self.index(&pos).unwrap()] { continue; }
+
+            // Moves cost the same both ways so the successors double as predecessors
+            for (next, step_cost) in self.successors(&pos) {
+                let index = self.index(&next).unwrap();
+                if cost + step_cost < costs[index] {
+                    costs[index] = cost + step_cost;
+                    open.push(Reverse((cost + step_cost, next.x, next.y)));
+                }
+            }
+        }
+
+        FlowField { goal: goal.clone(), costs }
+    }
+}
+
+pub struct FlowField {
+    goal: GridPos,
+    costs: Vec<u32>,
+}
+
+impl FlowField {
+    pub fn goal(&self) -> &GridPos {
+        &self.goal
+    }
+
+    pub fn cost(&self, nav: &NavGrid, pos: &GridPos) -> Option<u32> {
+        nav.index(pos).map(|index| self.costs[index]).filter(|cost| *cost != UNREACHABLE)
+    }
+
+    // The neighbouring tile that is the cheapest way on to the goal, None at the goal or where it can't be reached
+    pub fn next_step(&self, nav: &NavGrid, pos: &GridPos) -> Option<GridPos> {
+        let here = self.cost(nav, pos)?;
+        nav.successors(pos).into_iter()
+            .filter_map(|(next, _cost)| self.cost(nav, &next).map(|cost| (next, cost)))
+            .filter(|(_next, cost)| *cost < here)
+            .min_by_key(|(_next, cost)| *cost)
+            .map(|(next, _cost)| next)
+    }
+
+    // Where to head next from a position when going to target, or None if this field doesn't lead there.
+    // Skips ahead along the field as far as there's a straight line so the route doesn't zig-zag between tiles.
+    pub fn step_towards(&self, nav: &NavGrid, from: Vec2, target: Vec2, radius: f32) -> Option<Vec2> {
+        if nav.world_to_grid(target).distance(&self.goal) > 1 {
+            return None;
+        }
+
+        let mut pos = nav.world_to_grid(from);
+        if pos == self.goal || nav.clear_line(from, target, radius) {
+            return Some(target);
+        }
+
+        let mut step = None;
+        for _ in 0..FLOW_LOOKAHEAD {
+            let next = match self.next_step(nav, &pos) {
+                Some(next) => next,
+                None => break,
+            };
+            let point = nav.grid_to_world(&next);
+            // Always take at least one step so a guard squeezed against a corner still gets somewhere
+            if step.is_some() && !nav.clear_line(from, point, radius) {
+                break;
+            }
+            step = Some(point);
+            pos = next;
+        }
+        return step;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::level_data::{TileValue, DIAGONAL_COST, STRAIGHT_COST};
+
+    // Grid of rooms joined by doorways, each with a pillar and a guard, and the player in the top left one
+    fn rooms_level(rooms_x: usize, rooms_y: usize) -> LevelTiles {
+        const ROOM: usize = 7;
+        let (width, height) = (rooms_x * ROOM + 1, rooms_y * ROOM + 1);
+        let mut text = "next\n".to_string();
+        for y in 0..height {
+            for x in 0..width {
+                let (room_x, room_y) = (x % ROOM, y % ROOM);
+                let tile = match (room_x, room_y) {
+                    (0, 3) | (3, 0) if x > 0 && y > 0 && x < width - 1 && y < height - 1 => ' ',
+                    (0, _) | (_, 0) => '#',
+                    _ if x == width - 1 || y == height - 1 => '#',
+                    (2, 2) => '#',
+                    (4, 4) if x < ROOM && y < ROOM => 'V',
+                    (4, 4) => 'X',
+                    _ => ' ',
+                };
+                text.push(tile);
+            }
+            text.push('\n');
+        }
+        LevelTiles::parse(&text).unwrap()
+    }
+
+    fn tiles_of(level: &LevelTiles, value: TileValue) -> Vec<GridPos> {
+        (0..level.tiles.len())
+            .filter(|index| level.tiles[*index] == value)
+            .map(|index| GridPos{x: (index % level.width) as i32, y: (index / level.width) as i32})
+            .collect()
+    }
+
+    #[test]
+    fn test_flow_field_matches_astar() {
+        let level = rooms_level(3, 2);
+        let nav = NavGrid::new(&level);
+        let player = tiles_of(&level, TileValue::Player)[0].clone();
+        let field = nav.flow_field(&player);
+
+        for guard in tiles_of(&level, TileValue::Enemy) {
+            let (_cells, astar_cost) = nav.find_cells(&guard, &player).unwrap();
+            assert_eq!(field.cost(&nav, &guard), Some(astar_cost));
+
+            // Following the field gets there in the same number of steps as the A* path
+            let mut pos = guard.clone();
+            let mut walked = 0;
+            while let Some(next) = field.next_step(&nav, &pos) {
+                walked += if next.x != pos.x && next.y != pos.y { DIAGONAL_COST } else { STRAIGHT_COST };
+                pos = next;
+            }
+            assert_eq!(pos, player);
+            assert_eq!(walked, astar_cost);
+        }
+    }
+
+    #[test]
+    fn test_flow_field_only_leads_to_its_goal() {
+        let level = LevelTiles::parse("next\n#######\n#V # X#\n#######\n").unwrap();
+        let nav = NavGrid::new(&level);
+        let field = nav.flow_field(&GridPos{x: 1, y: 1});
+
+        assert_eq!(field.cost(&nav, &GridPos{x: 5, y: 1}), None, "Other side of the wall");
+        assert_eq!(field.next_step(&nav, &GridPos{x: 1, y: 1}), None);
+
+        let from = nav.grid_to_world(&GridPos{x: 2, y: 1});
+        let goal = nav.grid_to_world(&GridPos{x: 1, y: 1});
+        assert_eq!(field.step_towards(&nav, from, goal, 20.0), Some(goal));
+        assert_eq!(field.step_towards(&nav, from, nav.grid_to_world(&GridPos{x: 5, y: 1}), 20.0), None);
+    }
+
+    const CORNER: &str = "next\n########\n#V     #\n#      #\n#####  #\n#####  #\n#####X #\n########\n";
+
+    #[test]
+    fn test_smooth_path_cuts_across_rooms() {
+        let level = LevelTiles::parse(CORNER).unwrap();
+        let nav = NavGrid::new(&level);
+        let from = nav.grid_to_world(&GridPos{x: 1, y: 1});
+        let across = nav.grid_to_world(&GridPos{x: 6, y: 2});
+
+        let path = nav.find_path(from, across).unwrap();
+        assert!(path.len() > 2);
+        assert_eq!(nav.smooth_path(from, &path, 20.0), vec![across], "Open room is crossed in a straight line");
+
+        // Around the corner it only turns where it has to, and never through a wall
+        let around = nav.grid_to_world(&GridPos{x: 5, y: 5});
+        let path = nav.find_path(from, around).unwrap();
+        let smoothed = nav.smooth_path(from, &path, 20.0);
+        assert!(smoothed.len() >= 2 && smoothed.len() < path.len() - 1);
+        assert_eq!(smoothed.last(), Some(&around));
+        let mut last = from;
+        for point in smoothed {
+            assert!(nav.clear_line(last, point, 20.0));
+            last = point;
+        }
+    }
+
+    #[test]
+    fn test_chase_skips_ahead_along_field() {
+        let level = LevelTiles::parse(CORNER).unwrap();
+        let nav = NavGrid::new(&level);
+        let goal = GridPos{x: 5, y: 5};
+        let field = nav.flow_field(&goal);
+        let from = nav.grid_to_world(&GridPos{x: 1, y: 1});
+        let target = nav.grid_to_world(&goal);
+
+        let step = field.step_towards(&nav, from, target, 20.0).unwrap();
+        assert!(nav.clear_line(from, step, 20.0));
+        assert!(nav.world_to_grid(step).distance(&GridPos{x: 1, y: 1}) > 2, "Heads for the corner rather than the next tile");
+
+        let in_sight = nav.grid_to_world(&GridPos{x: 5, y: 3});
+        assert_eq!(field.step_towards(&nav, in_sight, target, 20.0), Some(target));
+    }
+
+    // Compares a frame of every guard on game.level chasing the player with A* against one shared flow field.
+    // Reads the level at run time so editing it never breaks the build, only this ignored bench.
+    // Run with: cargo test --release bench_chasing_guards -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_chasing_guards() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/levels/game.level");
+        let level = LevelTiles::parse(&std::fs::read_to_string(path).unwrap()).unwrap();
+        let nav = NavGrid::new(&level);
+        let player = nav.grid_to_world(&tiles_of(&level, TileValue::Player)[0]);
+        let guards = tiles_of(&level, TileValue::Enemy).iter().map(|pos| nav.grid_to_world(pos)).collect::<Vec<Vec2>>();
+        let frames = 200;
+
+        let start = std::time::Instant::now();
+        for _ in 0..frames {
+            for guard in guards.iter() {
+                assert!(nav.find_path(*guard, player).is_some());
+            }
+        }
+        let astar_time = start.elapsed() / frames;
+
+        let start = std::time::Instant::now();
+        for _ in 0..frames {
+            let field = nav.flow_field(&nav.world_to_grid(player));
+            for guard in guards.iter() {
+                assert!(field.step_towards(&nav, *guard, player, 20.0).is_some());
+            }
+        }
+        let field_time = start.elapsed() / frames;
+
+        println!("{} guards chasing on game.level, per frame:", guards.len());
+        println!("  A* for every guard: {:?}", astar_time);
+        println!("  shared flow field:  {:?} (rebuilt every frame, normally only when the player changes tile)", field_time);
+    }
+}
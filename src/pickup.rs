@@ -35,5 +35,6 @@ pub fn spawn_pickup(
     })
     .insert(ColliderPositionSync::Discrete)
     .insert(Pickup {value: 1})
+    .insert(crate::level::LevelEntity)
     ;
 }
\ No newline at end of file
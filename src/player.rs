@@ -1,13 +1,27 @@
-use bevy::{math::Vec3Swizzles, prelude::*, };
+use bevy::{ecs::schedule::StateError, math::Vec3Swizzles, prelude::*, };
 use bevy_rapier2d::prelude::*;
 use nalgebra::{Vector2, vector};
 
 use crate::particles;
+use crate::ai::NoiseEvent;
 use crate::gamestate::{GameState, Score};
 use crate::pickup::Pickup;
+use crate::smoke::SmokeCloud;
+
+// Footsteps are heard this far away when moving at full speed, and this far when sneaking
+const FOOTSTEP_NOISE_RADIUS: f32 = 250.0;
+const SNEAK_NOISE_RADIUS: f32 = 60.0;
+// Seconds between footstep noises
+const FOOTSTEP_INTERVAL: f32 = 0.4;
+const SNEAK_SPEED_FACTOR: f32 = 0.5;
+
+const PICKUP_NOISE_RADIUS: f32 = 200.0;
+const SMOKE_BOMB_NOISE_RADIUS: f32 = 400.0;
 
 pub struct PlayerMovement {
     pub speed: f32,
+    pub sneaking: bool,
+    footstep_timer: f32,
 }
 
 pub struct PlayerShooting {
@@ -27,6 +41,7 @@ impl Plugin for PlayerPlugin {
         app.add_system_set(SystemSet::on_update(GameState::Playing)
             .with_system(player_movement_system.system())
             .with_system(player_shoot_system.system())
+            .with_system(smoke_fade_system.system())
             .with_system(follow_camera_objstep.system())
             .with_system(follow_camera_camstep.system())
             .with_system(process_collision_events.system())
@@ -37,9 +52,11 @@ impl Plugin for PlayerPlugin {
 pub fn player_movement_system(
     keyboard_input: Res<Input<KeyCode>>,
     rapier_parameters: Res<RapierConfiguration>,
-    mut query: Query<(&PlayerMovement, &mut RigidBodyVelocity)>
+    time: Res<Time>,
+    mut noise_events: EventWriter<NoiseEvent>,
+    mut query: Query<(&mut PlayerMovement, &mut RigidBodyVelocity, &Transform)>
 ) {
-    if let Ok((player, mut rb_vels)) = query.single_mut() {
+    if let Ok((mut player, mut rb_vels, transform)) = query.single_mut() {
         let mut y_movement = 0.0;
         let mut x_movement = 0.0; 
         if keyboard_input.pressed(KeyCode::W) {
@@ -55,10 +72,23 @@ pub fn player_movement_system(
             x_movement += 1.0;
         }
 
+        // Holding shift sneaks, slower but much quieter
+        player.sneaking = keyboard_input.pressed(KeyCode::LShift) || keyboard_input.pressed(KeyCode::RShift);
+        let speed = if player.sneaking { player.speed * SNEAK_SPEED_FACTOR } else { player.speed };
+
         let mut movement = vector![x_movement, y_movement];
         if movement != Vector2::zeros() 
         {
-            movement = movement.normalize() * (1.0 / rapier_parameters.scale) * player.speed;
+            movement = movement.normalize() * (1.0 / rapier_parameters.scale) * speed;
+
+            player.footstep_timer += time.delta_seconds();
+            if player.footstep_timer >= FOOTSTEP_INTERVAL {
+                player.footstep_timer = 0.0;
+                noise_events.send(NoiseEvent {
+                    position: transform.translation.xy(),
+                    radius: if player.sneaking { SNEAK_NOISE_RADIUS } else { FOOTSTEP_NOISE_RADIUS },
+                });
+            }
         }
 
         rb_vels.linvel = movement;
@@ -72,15 +102,20 @@ pub fn player_shoot_system(
     mut commands: Commands,
     time: Res<Time>,
     rapier_config: Res<RapierConfiguration>,
+    mut noise_events: EventWriter<NoiseEvent>,
     mut query: Query<(&mut PlayerShooting, &Transform)>
 ) {
     if let Ok((mut player, transform)) = query.single_mut() {
         if player.bombs > 0 && keyboard_input.just_pressed(KeyCode::Space) {
             let block_size = 100.0;
+            let lifetime_min = 8.0;
+            let lifetime_max = 15.0;
 
             player.cooldown = 0.0;
             player.bombs -= 1;
 
+            noise_events.send(NoiseEvent { position: transform.translation.xy(), radius: SMOKE_BOMB_NOISE_RADIUS });
+
             commands.spawn()
                 .insert(particles::BurstParticleEmitter {
                     quantity: 100,
@@ -91,12 +126,13 @@ pub fn player_shoot_system(
                     speed_max: 250.0,
                     particle_drag: 4.0,
                     particle_size: Vec2::new(30.0, 30.0),
-                    lifetime_min: 8.0,
-                    lifetime_max: 15.0,
+                    lifetime_min,
+                    lifetime_max,
                     material: player.smoke_mat.clone(),
                 })
                 .insert(Transform::from_translation(transform.translation))
                 .insert(crate::lighting::DynamicLightBlocker{size: block_size})
+                .insert(SmokeCloud::new(block_size, lifetime_min, lifetime_max))
                 .insert_bundle(ColliderBundle {
                     position: [transform.translation.x / rapier_config.scale, transform.translation.y / rapier_config.scale].into(),
                     shape: ColliderShape::ball(block_size * 0.5 / rapier_config.scale),
@@ -117,6 +153,17 @@ pub fn player_shoot_system(
     }
 }
 
+// Shrinks the light blocker along with the cloud as its particles die off
+pub fn smoke_fade_system(
+    time: Res<Time>,
+    mut query: Query<(&mut SmokeCloud, &mut crate::lighting::DynamicLightBlocker)>,
+) {
+    for (mut cloud, mut blocker) in query.iter_mut() {
+        cloud.age += time.delta_seconds();
+        blocker.size = cloud.current_size();
+    }
+}
+
 pub fn follow_camera_camstep(
     follow_query: Query<&CamFollow>,
     mut camera_query: Query<&mut Transform, With<crate::MainCam>>,
@@ -143,6 +190,8 @@ pub fn spawn_player(
     materials: &mut ResMut<Assets<ColorMaterial>>,
     rapier_config: &Res<RapierConfiguration>,
     asset_server: &Res<AssetServer>,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    render_data: &ResMut<crate::lighting::LightRenderData>,
 ) {
     // Load sprite
     let circle_texture_handle: Handle<Texture> = asset_server.load("sprites/circle.png");
@@ -153,7 +202,7 @@ pub fn spawn_player(
 
     let collider_size = sprite_size_x / rapier_config.scale;
 
-    commands
+    let player = commands
     .spawn()
     .insert_bundle(SpriteBundle {
         material: materials.add(circle_texture_handle.into()),
@@ -171,20 +220,17 @@ pub fn spawn_player(
         ..Default::default()
     })
     .insert(ColliderPositionSync::Discrete)
-    .insert(PlayerMovement {speed: 200.0})
+    .insert(PlayerMovement {speed: 200.0, sneaking: false, footstep_timer: 0.0})
     .insert(PlayerShooting {smoke_mat: materials.add(smoke_texture_handle.into()), bombs: 3 ,cooldown: 0.})
     .insert(crate::lighting::DynamicLightBlocker{size: 20.0})
     .insert( CamFollow{position: Vec2::default()})
-    ;
+    .insert(crate::level::LevelEntity)
+    .id();
 
-    
-    commands
-    .spawn()
-    .insert(Transform::from_xyz(position.x, position.y, 0.0))
-    .insert(crate::visibility::VisChecker{radius: 100.0, visible: true})
-    //.insert(crate::visibility::VisDebug)
-    ;
-    
+    // How far the player can see around themselves in the dark
+    let personal_light = crate::lighting::spawn_personal_light(commands, meshes, render_data, 100.0);
+    //commands.entity(personal_light).insert(crate::visibility::VisDebug);
+    commands.entity(player).push_children(&[personal_light]);
 }
 
 
@@ -194,23 +240,29 @@ fn process_collision_events(
     mut score: ResMut<Score>,
     mut intersection_events: EventReader<IntersectionEvent>,
     mut contact_events: EventReader<ContactEvent>,
+    mut noise_events: EventWriter<NoiseEvent>,
     player_query: Query<Entity, With<PlayerMovement>>,
     enemy_query: Query<Entity, With<crate::ai::AiPerception>>,
-    pickup_query: Query<(Entity, &Pickup), With<Pickup>>,
+    pickup_query: Query<(Entity, &Pickup, &Transform), With<Pickup>>,
     asset_server: Res<AssetServer>, 
     audio: Res<Audio>
 ) {
+    let mut collected = false;
     for intersection_event in intersection_events.iter() {
         if player_query.get(intersection_event.collider1.entity()).is_ok() {
             if let Ok(pair) = pickup_query.get(intersection_event.collider2.entity()) {
                 score.value += pair.1.value;
+                collected = true;
                 commands.entity(pair.0).despawn_recursive();
+                noise_events.send(NoiseEvent { position: pair.2.translation.xy(), radius: PICKUP_NOISE_RADIUS });
             }
         }
         else if player_query.get(intersection_event.collider2.entity()).is_ok() {
             if let Ok(pair) = pickup_query.get(intersection_event.collider1.entity()) {
                 score.value += pair.1.value;
+                collected = true;
                 commands.entity(pair.0).despawn_recursive();
+                noise_events.send(NoiseEvent { position: pair.2.translation.xy(), radius: PICKUP_NOISE_RADIUS });
 
                 let fx = asset_server.load("audio/sfx/Stutter_Beep.mp3");
                 audio.play(fx);
@@ -239,4 +291,13 @@ fn process_collision_events(
         
         
     }
+
+    // Only reached if the player wasn't caught this frame
+    if collected && score.max > 0 && score.value >= score.max {
+        match state.set(GameState::LevelComplete) {
+            // Leaving play the same frame, like going back to the editor with F2, wins over finishing the level
+            Err(StateError::StateAlreadyQueued) => (),
+            result => result.unwrap(),
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,157 @@
+use bevy::prelude::*;
+use rand::{seq::SliceRandom, Rng};
+use std::collections::HashSet;
+
+use crate::level_data::{GridPos, LevelTiles};
+
+// Guards searching together try to keep their chosen cells at least this many tiles apart
+const CLAIM_SPACING: u32 = 4;
+// How much less likely a cell near another guard's is to be picked
+const CLAIMED_WEIGHT: f32 = 0.05;
+// How much more likely a cell straight ahead of where the player was heading is to be picked
+const HEADING_WEIGHT: f32 = 3.0;
+// Further cells are less likely, by this much per step from the last sighting
+const STEP_FALLOFF: f32 = 0.1;
+// Tiles around a searching guard that count as looked at
+const LOOK_RADIUS: i32 = 1;
+
+// Where a guard has looked since losing the player, so it keeps moving on to new places instead of
+// wandering back over the same tiles. A search gives up after checking a set number of cells.
+pub struct SearchPlan {
+    origin: GridPos,
+    // Direction the player was moving when last seen, zero if that isn't known
+    heading: Vec2,
+    searched: HashSet<GridPos>,
+    // Cells left to check before giving up
+    budget: u32,
+}
+
+impl SearchPlan {
+    pub fn new(origin: GridPos, heading: Vec2, budget: u32) -> SearchPlan {
+        SearchPlan {
+            origin,
+            heading,
+            searched: HashSet::new(),
+            budget,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.budget == 0
+    }
+
+    // The guard is standing here and can see the tiles right around it
+    pub fn mark_searched(&mut self, pos: &GridPos) {
+        for y in -LOOK_RADIUS..=LOOK_RADIUS {
+            for x in -LOOK_RADIUS..=LOOK_RADIUS {
+                self.searched.insert(GridPos{x: pos.x + x, y: pos.y + y});
+            }
+        }
+    }
+
+    // Picks an unsearched cell within max_steps moves of the last sighting, or None once the budget is spent or
+    // everything in reach has been looked at. Nearby cells ahead of where the player was going are the likeliest
+    // picks, and cells near the ones other guards are heading to are avoided so a group spreads out.
+    pub fn next_cell(&mut self, level: &LevelTiles, max_steps: u32, claimed: &[GridPos], rng: &mut impl Rng) -> Option<GridPos> {
+        if self.is_finished() {
+            return None;
+        }
+
+        let candidates = level.cells_within(&self.origin, max_steps).into_iter()
+            .filter(|(cell, _steps)| !self.searched.contains(cell))
+            .map(|(cell, steps)| {
+                let weight = self.cell_weight(&cell, steps, claimed);
+                (cell, weight)
+            })
+            .collect::<Vec<(GridPos, f32)>>();
+
+        let (cell, _weight) = candidates.choose_weighted(rng, |(_cell, weight)| *weight).ok()?;
+        self.budget -= 1;
+        return Some(cell.clone());
+    }
+
+    fn cell_weight(&self, cell: &GridPos, steps: u32, claimed: &[GridPos]) -> f32 {
+        let offset = Vec2::new((cell.x - self.origin.x) as f32, (cell.y - self.origin.y) as f32);
+        let ahead = offset.try_normalize().map_or(0.0, |direction| direction.dot(self.heading).max(0.0));
+        let spread = if claimed.iter().any(|other| cell.distance(other) < CLAIM_SPACING) { CLAIMED_WEIGHT } else { 1.0 };
+
+        (1.0 + HEADING_WEIGHT * ahead) * spread / (1.0 + STEP_FALLOFF * steps as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    const ROOM: &str = "next\n#################\n#               #\n#       V       #\n#               #\n#################\n";
+
+    #[test]
+    fn test_search_cells_are_reachable_and_new() {
+        let level = LevelTiles::parse(ROOM).unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let origin = GridPos{x: 8, y: 2};
+        let mut plan = SearchPlan::new(origin.clone(), Vec2::default(), 100);
+        let reachable = level.cells_within(&origin, 4).into_iter().map(|(cell, _steps)| cell).collect::<HashSet<GridPos>>();
+
+        let mut picked = HashSet::new();
+        while let Some(cell) = plan.next_cell(&level, 4, &[], &mut rng) {
+            assert!(reachable.contains(&cell));
+            assert!(picked.insert(cell.clone()), "Searched cells aren't picked again");
+            plan.mark_searched(&cell);
+        }
+        assert!(!plan.is_finished(), "Ran out of cells before the budget");
+
+        let mut walled_in = SearchPlan::new(GridPos{x: 0, y: 0}, Vec2::default(), 100);
+        assert_eq!(walled_in.next_cell(&level, 4, &[], &mut rng), None);
+    }
+
+    #[test]
+    fn test_search_ends_after_budget() {
+        let level = LevelTiles::parse(ROOM).unwrap();
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut plan = SearchPlan::new(GridPos{x: 8, y: 2}, Vec2::default(), 3);
+
+        for _ in 0..3 {
+            assert!(plan.next_cell(&level, 6, &[], &mut rng).is_some());
+        }
+        assert!(plan.is_finished());
+        assert_eq!(plan.next_cell(&level, 6, &[], &mut rng), None);
+    }
+
+    #[test]
+    fn test_search_follows_heading() {
+        let level = LevelTiles::parse(ROOM).unwrap();
+        let mut rng = StdRng::seed_from_u64(3);
+        let origin = GridPos{x: 8, y: 2};
+
+        let mut ahead = 0;
+        for _ in 0..200 {
+            let mut plan = SearchPlan::new(origin.clone(), Vec2::new(1.0, 0.0), 1);
+            let cell = plan.next_cell(&level, 6, &[], &mut rng).unwrap();
+            if cell.x > origin.x { ahead += 1; }
+        }
+        assert!(ahead > 140, "Only {} of 200 cells were ahead of the player", ahead);
+    }
+
+    #[test]
+    fn test_search_cells_spread_out() {
+        let level = LevelTiles::parse(ROOM).unwrap();
+        let mut rng = StdRng::seed_from_u64(4);
+        let origin = GridPos{x: 8, y: 2};
+        let claimed = vec![GridPos{x: 5, y: 2}];
+
+        let mut spread = 0;
+        for _ in 0..100 {
+            let mut plan = SearchPlan::new(origin.clone(), Vec2::default(), 1);
+            let cell = plan.next_cell(&level, 6, &claimed, &mut rng).unwrap();
+            if cell.distance(&claimed[0]) >= CLAIM_SPACING { spread += 1; }
+        }
+        assert!(spread > 90, "Only {} of 100 cells kept away from the other guard", spread);
+
+        // With no room to spread out, a claimed cell is still better than standing around
+        let crowded = level.cells_within(&origin, 1).into_iter().map(|(cell, _steps)| cell).collect::<Vec<GridPos>>();
+        let mut plan = SearchPlan::new(origin.clone(), Vec2::default(), 1);
+        assert!(plan.next_cell(&level, 1, &crowded, &mut rng).is_some());
+    }
+}
@@ -0,0 +1,95 @@
+use bevy::prelude::*;
+
+// Cloud left by a smoke bomb. It blocks guards' sight and their light while it lasts, thinning out as the particles die off.
+// The light blocker and guard sight both use current_size so what a spotlight lights up and what a guard sees always agree.
+pub struct SmokeCloud {
+    // Width when thickest
+    pub size: f32,
+    pub age: f32,
+    // Seconds the cloud stays thick, then it thins out until it is gone at lifetime
+    pub thick_time: f32,
+    pub lifetime: f32,
+}
+
+impl SmokeCloud {
+    pub fn new(size: f32, thick_time: f32, lifetime: f32) -> SmokeCloud {
+        SmokeCloud { size, age: 0.0, thick_time, lifetime }
+    }
+
+    // 1 while thick down to 0 once the cloud is gone
+    pub fn density(&self) -> f32 {
+        if self.age <= self.thick_time {
+            return 1.0;
+        }
+        return (1.0 - (self.age - self.thick_time) / (self.lifetime - self.thick_time)).clamp(0.0, 1.0);
+    }
+
+    pub fn current_size(&self) -> f32 {
+        self.size * self.density()
+    }
+}
+
+// Whether the line between two points passes through any of the square clouds, given as center and width
+pub fn smoke_blocks_line(from: Vec2, to: Vec2, clouds: &[(Vec2, f32)]) -> bool {
+    clouds.iter().any(|(center, size)| segment_hits_square(from, to, *center, *size))
+}
+
+// Slab test of a line segment against an axis aligned square
+fn segment_hits_square(from: Vec2, to: Vec2, center: Vec2, size: f32) -> bool {
+    if size <= 0.0 {
+        return false;
+    }
+
+    let half = 0.5 * size;
+    let direction = to - from;
+    let mut t_min: f32 = 0.0;
+    let mut t_max: f32 = 1.0;
+
+    for (start, delta, low, high) in [
+        (from.x, direction.x, center.x - half, center.x + half),
+        (from.y, direction.y, center.y - half, center.y + half),
+    ].iter() {
+        if delta.abs() < f32::EPSILON {
+            if start < low || start > high {
+                return false;
+            }
+            continue;
+        }
+        let t1 = (low - start) / delta;
+        let t2 = (high - start) / delta;
+        t_min = t_min.max(t1.min(t2));
+        t_max = t_max.min(t1.max(t2));
+        if t_min > t_max {
+            return false;
+        }
+    }
+    return true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_smoke_thins_out() {
+        let mut cloud = SmokeCloud::new(100.0, 8.0, 15.0);
+        assert_eq!(cloud.current_size(), 100.0);
+        cloud.age = 8.0;
+        assert_eq!(cloud.density(), 1.0);
+        cloud.age = 11.5;
+        assert!((cloud.current_size() - 50.0).abs() < 0.001);
+        cloud.age = 20.0;
+        assert_eq!(cloud.density(), 0.0);
+    }
+
+    #[test]
+    fn test_smoke_blocks_line() {
+        let cloud = [(Vec2::new(0.0, 0.0), 100.0)];
+        assert!(smoke_blocks_line(Vec2::new(-200.0, 0.0), Vec2::new(200.0, 0.0), &cloud), "Straight through");
+        assert!(smoke_blocks_line(Vec2::new(-200.0, -100.0), Vec2::new(200.0, 100.0), &cloud), "Diagonally through");
+        assert!(smoke_blocks_line(Vec2::new(0.0, 0.0), Vec2::new(0.0, 300.0), &cloud), "Seen from inside");
+        assert!(!smoke_blocks_line(Vec2::new(-200.0, 60.0), Vec2::new(200.0, 60.0), &cloud), "Passes above");
+        assert!(!smoke_blocks_line(Vec2::new(-200.0, 0.0), Vec2::new(-100.0, 0.0), &cloud), "Stops short");
+        assert!(!smoke_blocks_line(Vec2::new(-200.0, 0.0), Vec2::new(200.0, 0.0), &[(Vec2::default(), 0.0)]), "Cloud is gone");
+    }
+}
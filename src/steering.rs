@@ -0,0 +1,86 @@
+use bevy::prelude::*;
+
+// Guards closer than this start easing away from each other
+const SEPARATION_RADIUS: f32 = 70.0;
+// How hard a guard sidesteps someone in front of it
+const PASSING_WEIGHT: f32 = 1.5;
+// Guards start veering off this far outside a smoke cloud
+const SMOKE_MARGIN: f32 = 30.0;
+const SMOKE_WEIGHT: f32 = 1.5;
+
+// Bends the velocity a guard wants so it keeps clear of other guards and smoke, never making it go faster.
+// Smoke is given as center and radius.
+pub fn steer(position: Vec2, desired: Vec2, others: &[Vec2], smoke: &[(Vec2, f32)]) -> Vec2 {
+    let speed = desired.length();
+    let forward = match desired.try_normalize() {
+        Some(forward) => forward,
+        None => return desired,
+    };
+
+    let mut push = Vec2::default();
+    for other in others {
+        let away = position - *other;
+        let distance = away.length();
+        if distance >= SEPARATION_RADIUS || distance == 0.0 { continue; }
+
+        let strength = 1.0 - distance / SEPARATION_RADIUS;
+        push += away / distance * strength;
+        // Someone in the way, everyone keeps to their right so two guards meeting head on slide past each other
+        if forward.dot(away) < 0.0 {
+            push += Vec2::new(forward.y, -forward.x) * strength * PASSING_WEIGHT;
+        }
+    }
+
+    for (center, radius) in smoke {
+        let away = position - *center;
+        let distance = away.length();
+        let reach = radius + SMOKE_MARGIN;
+        if distance >= reach || distance == 0.0 { continue; }
+
+        push += away / distance * (1.0 - distance / reach) * SMOKE_WEIGHT;
+    }
+
+    return (forward + push).try_normalize().map_or(Vec2::default(), |direction| direction * speed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nothing_nearby_keeps_course() {
+        let desired = Vec2::new(100.0, 0.0);
+        let far_guard = Vec2::new(0.0, 500.0);
+        assert_eq!(steer(Vec2::default(), desired, &[far_guard], &[(Vec2::new(-500.0, 0.0), 50.0)]), desired);
+        assert_eq!(steer(Vec2::default(), Vec2::default(), &[Vec2::new(10.0, 0.0)], &[]), Vec2::default());
+    }
+
+    #[test]
+    fn test_guards_meeting_head_on_pass() {
+        let left = Vec2::new(-30.0, 0.0);
+        let right = Vec2::new(30.0, 0.0);
+        let from_left = steer(left, Vec2::new(100.0, 0.0), &[right], &[]);
+        let from_right = steer(right, Vec2::new(-100.0, 0.0), &[left], &[]);
+
+        // Each veers to its own right, which is opposite sides of the corridor
+        assert!(from_left.y < 0.0);
+        assert!(from_right.y > 0.0);
+        assert!(from_left.length() <= 100.001 && from_right.length() <= 100.001);
+    }
+
+    #[test]
+    fn test_guards_spread_out() {
+        let position = Vec2::new(0.0, 10.0);
+        let steered = steer(position, Vec2::new(100.0, 0.0), &[Vec2::new(0.0, -10.0)], &[]);
+        assert!(steered.y > 0.0, "Moves away from a guard alongside");
+        assert!(steered.x > 0.0, "Still heads the way it wanted to");
+    }
+
+    #[test]
+    fn test_smoke_is_avoided() {
+        let position = Vec2::new(0.0, -40.0);
+        let steered = steer(position, Vec2::new(100.0, 0.0), &[], &[(Vec2::new(20.0, 0.0), 50.0)]);
+        assert!(steered.y < 0.0, "Veers around the cloud");
+        assert!(steered.x > 0.0);
+    }
+}